@@ -0,0 +1,182 @@
+use crate::{get_env, get_env_or};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use reqwest::{Client, StatusCode};
+use serde_json::Value as JsonValue;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/*
+Code Summary:
+This module sends requests to the A11yWatch API and takes care of its JWT. If A11Y_EMAIL and A11Y_PASSWORD are set, the service logs in to A11yWatch, caches the returned JWT and logs in again shortly before the token expires. If A11yWatch still answers a request with 401 Unauthorized, the token is refreshed and the request is sent once more. Without credentials, the static A11Y_JWT variable is used as before.
+
+Variables:
+    CachedToken:
+        A JWT returned by the login endpoint and the unix time it expires at.
+
+    TOKEN:
+        The cached token, shared by every request.
+
+    A11yError:
+        An enum type that specifies the possible errors that can occur while talking to A11yWatch.
+
+Functions:
+    post:
+        Sends a JSON body to an A11yWatch endpoint (e.g. "scan" or "crawl") with the current JWT and returns the JSON response. Retries once with a fresh token on 401.
+
+    token:
+        Returns the cached JWT, logging in first if there is none or it expires within A11Y_JWT_REFRESH seconds.
+
+    login:
+        Logs in to A11yWatch with A11Y_EMAIL and A11Y_PASSWORD and reads the expiry from the token's "exp" claim.
+
+Docker Vars:
+    A11Y_URL:
+        URL of the A11yWatch API.
+
+    A11Y_EMAIL, A11Y_PASSWORD:
+        Credentials of the A11yWatch account used to log in.
+
+    A11Y_JWT:
+        Static JWT, only used when A11Y_EMAIL or A11Y_PASSWORD is not set.
+
+    A11Y_JWT_REFRESH:
+        Number of seconds before expiry at which the token is refreshed. Defaults to 300.
+
+Output:
+    post returns the parsed JSON response of A11yWatch.
+
+Errors:
+    Config:
+        A required environment variable is missing.
+
+    Login:
+        Logging in to A11yWatch failed or returned no token.
+
+    Unauthorized:
+        A11yWatch answered 401 Unauthorized even after refreshing the token.
+
+    Request:
+        The request could not be sent.
+
+    Response:
+        The response could not be parsed as JSON.
+*/
+
+struct CachedToken {
+    jwt: String,
+    expires: i64,
+}
+
+static TOKEN: Mutex<Option<CachedToken>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum A11yError {
+    Config(String),
+    Login(String),
+    Unauthorized,
+    Request(reqwest::Error),
+    Response(reqwest::Error),
+}
+
+// Credentials for logging in, if configured
+fn credentials() -> Option<(String, String)> {
+    match (get_env("A11Y_EMAIL"), get_env("A11Y_PASSWORD")) {
+        (Ok(email), Ok(password)) => Some((email, password)),
+        _ => None,
+    }
+}
+
+// Log in to A11yWatch and read the expiry of the returned token
+async fn login(client: &Client, email: &str, password: &str) -> Result<CachedToken, A11yError> {
+    let response = client
+        .post(format!(
+            "{}/login",
+            get_env("A11Y_URL").map_err(A11yError::Config)?
+        ))
+        .header("Accept", "application/json")
+        .header("Content-Type", "application/json")
+        .body(serde_json::json!({"email": email, "password": password}).to_string())
+        .timeout(Duration::from_secs(60))
+        .send()
+        .await
+        .map_err(|e| A11yError::Login(format!("failed to send login request: {}", e)))?
+        .json::<JsonValue>()
+        .await
+        .map_err(|e| A11yError::Login(format!("failed to parse login response: {}", e)))?;
+    let jwt = response
+        .pointer("/data/jwt")
+        .and_then(|jwt| jwt.as_str())
+        .ok_or_else(|| A11yError::Login(format!("no token in login response: {}", response)))?
+        .to_owned();
+
+    // Read the "exp" claim, assume an hour if the token has none
+    let expires = jwt
+        .split('.')
+        .nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice::<JsonValue>(&payload).ok())
+        .and_then(|claims| claims.get("exp").and_then(|exp| exp.as_i64()))
+        .unwrap_or_else(|| Utc::now().timestamp() + 3600);
+    Ok(CachedToken { jwt, expires })
+}
+
+// Get the JWT to send, logging in if the cached token is missing, expiring or rejected
+async fn token(client: &Client, refresh: bool) -> Result<String, A11yError> {
+    let (email, password) = match credentials() {
+        Some(credentials) => credentials,
+        None => return get_env("A11Y_JWT").map_err(A11yError::Config),
+    };
+    let margin = get_env_or("A11Y_JWT_REFRESH", 300);
+    if !refresh {
+        if let Some(cached) = TOKEN.lock().unwrap().as_ref() {
+            if cached.expires - margin > Utc::now().timestamp() {
+                return Ok(cached.jwt.clone());
+            }
+        }
+    }
+    let fresh = login(client, &email, &password).await?;
+    let jwt = fresh.jwt.clone();
+    *TOKEN.lock().unwrap() = Some(fresh);
+    Ok(jwt)
+}
+
+// Send a JSON body to an A11yWatch endpoint and parse the JSON response
+pub async fn post(
+    client: &Client,
+    endpoint: &str,
+    body: &JsonValue,
+) -> Result<JsonValue, A11yError> {
+    let url = format!(
+        "{}/{}",
+        get_env("A11Y_URL").map_err(A11yError::Config)?,
+        endpoint
+    );
+    let mut refresh = false;
+    loop {
+        let response = client
+            .post(&url)
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+            .header("Authorization", token(client, refresh).await?)
+            .body(body.to_string())
+            .timeout(Duration::from_secs(600)) // Wait up to 10 minutes
+            .send()
+            .await
+            .map_err(A11yError::Request)?;
+
+        // Retry once with a fresh token if the token was rejected
+        if response.status() == StatusCode::UNAUTHORIZED {
+            if refresh || credentials().is_none() {
+                return Err(A11yError::Unauthorized);
+            }
+            refresh = true;
+            continue;
+        }
+        return response
+            .json::<JsonValue>()
+            .await
+            .map_err(A11yError::Response);
+    }
+}
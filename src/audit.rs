@@ -0,0 +1,292 @@
+use crate::auth::Caller;
+use crate::bigquery::read_up_targets;
+use crate::bigquery::store as bq_store;
+use crate::up::{fetch, read_settings};
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use reqwest::header::HeaderMap;
+use reqwest::{redirect::Policy, Client, Url};
+use rocket::post;
+use rocket::{http::Status, response::status, Data};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::io::Read;
+use std::time::Duration;
+
+/*
+Code Summary:
+This module audits domains for the HTTPS and security header requirements of federal sites (M-15-13 and BOD 18-01). For each domain it requests http://domain/ and https://domain/ with the redirect handling of up.rs and checks that:
+    - plain HTTP redirects to HTTPS,
+    - the HTTPS response has a Strict-Transport-Security header with a max-age of at least a year, includeSubDomains and preload,
+    - the Content-Security-Policy, X-Content-Type-Options (nosniff), Referrer-Policy and X-Frame-Options headers are present.
+Every audit is stored as one row of the security_audits table with the outcome of each check and a pass/fail summary.
+
+Variables:
+    caller :
+        The API key, signed request or bearer token that made the request. A single target needs the "audit:run" scope, auditing every up target needs "audit:cycle".
+    HSTS_MIN_MAX_AGE :
+        The smallest HSTS max-age that passes, one year as required by BOD 18-01.
+    AuditResult :
+        The outcome of one audit, stored as one row of the security_audits table.
+
+Functions:
+    catch_audit :
+        Rocket endpoint for POST /audit. Audits the "target" in the body, or the domains of every HTTP target in the up_targets table if no target is given, and stores the results.
+    audit :
+        Runs every check against one domain within the timeout.
+    domain :
+        Reads the host name from a URL or host.
+    header :
+        Reads a response header as a string.
+
+Docker Vars:
+    UP_CONCURRENCY, UP_TIMEOUT :
+        Domains audited at the same time and seconds per request, as for /up. Both can be overridden per request with "concurrency" and "timeout" in the body.
+
+Output:
+    For a single target, the AuditResult:
+        {"domain", "https_redirect", "redirect_url", "hsts", "hsts_max_age", "hsts_include_subdomains", "hsts_preload", "csp", "x_content_type_options", "referrer_policy", "x_frame_options", "passed", "failed", "failed_checks", "pass", "error", "audited_at"}
+    failed_checks is a comma separated list of the checks that failed, pass is true if none did. If a request fails, error holds the reason and the checks that depend on it fail.
+
+    For all targets, the output is "audited {} domain(s), {} passed".
+
+Errors:
+    failed to read body data: {} / failed to parse body data: {} :
+        The body could not be read or is not a JSON object.
+    target must be a string :
+        The target in the body is not a string.
+    failed to read target list from google big query: {} :
+        The up targets could not be read.
+    failed to store data to google big query: {} :
+        The results could not be stored.
+*/
+
+// One year, the minimum HSTS max-age of BOD 18-01
+const HSTS_MIN_MAX_AGE: u64 = 31_536_000;
+
+// The outcome of one audit, one row of the security_audits table
+#[derive(Serialize, Debug, Default)]
+pub struct AuditResult {
+    pub domain: String,
+    pub https_redirect: bool,
+    pub redirect_url: Option<String>,
+    pub hsts: Option<String>,
+    pub hsts_max_age: Option<u64>,
+    pub hsts_include_subdomains: bool,
+    pub hsts_preload: bool,
+    pub csp: bool,
+    pub x_content_type_options: bool,
+    pub referrer_policy: bool,
+    pub x_frame_options: bool,
+    pub passed: u32,
+    pub failed: u32,
+    pub failed_checks: String,
+    pub pass: bool,
+    pub error: Option<String>,
+    pub audited_at: String,
+}
+
+#[post("/audit", data = "<raw_data>")]
+pub(crate) fn catch_audit(
+    raw_data: Data,
+    caller: Caller,
+) -> Result<String, rocket::response::status::Custom<std::string::String>> {
+    // Read the request body, limited to 1 MB, and check the signature of signed requests
+    let mut buf = Vec::new();
+    raw_data
+        .open()
+        .take(1024 * 1024)
+        .read_to_end(&mut buf)
+        .map_err(|e| {
+            status::Custom(
+                Status::BadRequest,
+                format!("failed to read body data: {}", e),
+            )
+        })?;
+    caller.verify_body(&buf)?;
+
+    // An empty body audits every up target
+    let data: serde_json::Map<String, JsonValue> = if buf.is_empty() {
+        serde_json::Map::new()
+    } else {
+        serde_json::from_slice(&buf).map_err(|e| {
+            status::Custom(
+                Status::BadRequest,
+                format!("failed to parse body data: {}", e),
+            )
+        })?
+    };
+
+    // The concurrency and timeout are read like those of /up
+    let (concurrency, settings) = read_settings(&data)?;
+    let client = Client::builder().redirect(Policy::none()).build().unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let store = |results: JsonValue| {
+        rt.block_on(bq_store(
+            "rusty_a11y".to_owned(),
+            "security_audits".to_owned(),
+            &results,
+        ))
+        .map_err(|e| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("failed to store data to google big query: {}", e),
+            )
+        })
+    };
+
+    if let Some(target) = data.get("target") {
+        // If a specific target is given, audit its domain
+        caller.require("audit:run")?;
+        let target = target.as_str().ok_or_else(|| {
+            status::Custom(Status::BadRequest, "target must be a string".to_owned())
+        })?;
+        let result = rt.block_on(audit(&client, &domain(target), settings.timeout));
+        let result = serde_json::to_value(result).unwrap();
+        store(result.clone())?;
+        Ok(result.to_string())
+    } else {
+        // If no specific target is given, audit the domain of every HTTP up target once
+        caller.require("audit:cycle")?;
+        // Rows rejected for their assertions still name a domain, but are left to /up to report
+        let (targets, _) = rt
+            .block_on(read_up_targets("rusty_a11y".to_owned()))
+            .map_err(|e| {
+                status::Custom(
+                    Status::InternalServerError,
+                    format!("failed to read target list from google big query: {}", e),
+                )
+            })?;
+        let mut domains: Vec<String> = targets
+            .iter()
+            .filter(|target| target.probe_type() == "http")
+            .map(|target| domain(&target.url))
+            .collect();
+        domains.sort();
+        domains.dedup();
+
+        let results: Vec<AuditResult> = rt.block_on(
+            stream::iter(domains.iter())
+                .map(|domain| audit(&client, domain, settings.timeout))
+                .buffer_unordered(concurrency)
+                .collect(),
+        );
+        let passed = results.iter().filter(|result| result.pass).count();
+        let msg = format!("audited {} domain(s), {} passed", results.len(), passed);
+        store(serde_json::to_value(results).unwrap())?;
+        Ok(msg)
+    }
+}
+
+// Read the host name of a URL, or use the target as it is
+fn domain(target: &str) -> String {
+    Url::parse(target)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_owned()))
+        .unwrap_or_else(|| target.trim_end_matches('/').to_owned())
+}
+
+// Read a header as a string, if it is present
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_owned())
+}
+
+// Audit the HTTPS redirect and security headers of a domain
+pub async fn audit(client: &Client, domain: &str, timeout: Duration) -> AuditResult {
+    let mut result = AuditResult {
+        domain: domain.to_owned(),
+        audited_at: Utc::now().format("%Y-%m-%d %H:%M:%S%.3f UTC").to_string(),
+        ..Default::default()
+    };
+    let mut errors = Vec::new();
+    let timed_out = |_| {
+        (
+            "timeout",
+            format!("no response within {} seconds", timeout.as_secs()),
+        )
+    };
+
+    // Plain HTTP must end up on an https URL
+    let http = format!("http://{}/", domain);
+    let mut final_url = http.clone();
+    match tokio::time::timeout(
+        timeout,
+        fetch(client, &http, |_, url| final_url = url.to_owned()),
+    )
+    .await
+    .unwrap_or_else(|e| Err(timed_out(e)))
+    {
+        Ok(_) => {
+            result.https_redirect = final_url.starts_with("https://");
+            result.redirect_url = Some(final_url);
+        }
+        Err((class, error)) => errors.push(format!("http ({}): {}", class, error)),
+    }
+
+    // The headers are read from the final HTTPS response
+    let https = format!("https://{}/", domain);
+    match tokio::time::timeout(timeout, fetch(client, &https, |_, _| {}))
+        .await
+        .unwrap_or_else(|e| Err(timed_out(e)))
+    {
+        Ok(response) => {
+            let headers = response.headers();
+            if let Some(hsts) = header(headers, "Strict-Transport-Security") {
+                let directives: Vec<String> = hsts
+                    .split(';')
+                    .map(|directive| directive.trim().to_lowercase())
+                    .collect();
+                result.hsts_max_age = directives.iter().find_map(|directive| {
+                    directive
+                        .strip_prefix("max-age=")
+                        .and_then(|age| age.trim_matches('"').parse().ok())
+                });
+                result.hsts_include_subdomains = directives
+                    .iter()
+                    .any(|directive| directive == "includesubdomains");
+                result.hsts_preload = directives.iter().any(|directive| directive == "preload");
+                result.hsts = Some(hsts);
+            }
+            result.csp = header(headers, "Content-Security-Policy").is_some();
+            result.x_content_type_options = header(headers, "X-Content-Type-Options")
+                .map_or(false, |value| value.eq_ignore_ascii_case("nosniff"));
+            result.referrer_policy = header(headers, "Referrer-Policy").is_some();
+            result.x_frame_options = header(headers, "X-Frame-Options").is_some();
+        }
+        Err((class, error)) => errors.push(format!("https ({}): {}", class, error)),
+    }
+
+    // Summarize the checks
+    let checks = [
+        ("https_redirect", result.https_redirect),
+        (
+            "hsts_max_age",
+            result
+                .hsts_max_age
+                .map_or(false, |age| age >= HSTS_MIN_MAX_AGE),
+        ),
+        ("hsts_include_subdomains", result.hsts_include_subdomains),
+        ("hsts_preload", result.hsts_preload),
+        ("csp", result.csp),
+        ("x_content_type_options", result.x_content_type_options),
+        ("referrer_policy", result.referrer_policy),
+        ("x_frame_options", result.x_frame_options),
+    ];
+    let failed: Vec<&str> = checks
+        .iter()
+        .filter(|(_, pass)| !pass)
+        .map(|(name, _)| *name)
+        .collect();
+    result.passed = (checks.len() - failed.len()) as u32;
+    result.failed = failed.len() as u32;
+    result.failed_checks = failed.join(",");
+    result.pass = failed.is_empty();
+    if !errors.is_empty() {
+        result.error = Some(errors.join("; "));
+    }
+    result
+}
@@ -1,34 +1,91 @@
-use crate::get_env;
+use crate::jwt::{JwtBearer, TokenError};
+use crate::limit::{LimitError, Limits, RateLimiter, RequestUsage};
+use crate::{get_env, get_env_or};
+use chrono::{NaiveDate, Utc};
+use hmac::{Hmac, Mac};
+use openssl::memcmp;
 use rocket::http::Status;
 use rocket::request::{self, FromRequest, Request};
-use rocket::Outcome;
+use rocket::response::status;
+use rocket::{Outcome, State};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
 
 /*
 Code Summary:
-This module handles the authorization of requests by verifying that the API key in the "x-auth" field of the header is valid. If the key is missing, invalid, or the environment variable containing the key is not set, the request is rejected with an appropriate status code. It defines a custom request guard, ApiKey, which checks if the x-auth header of an incoming request matches a given API key in the environment. The from_request method of ApiKey checks the header and environment variables, then returns an Outcome that is either a ApiKey or an ApiKeyError.
+This module handles the authorization of requests by verifying that the API key in the "x-auth" field of the header belongs to a known, unexpired key in the key registry. Each key in the registry has a name, an owner, an optional expiry date and a set of scopes that decide which endpoints it may call. If the key is missing, unknown, expired, or no keys are configured, the request is rejected with an appropriate status code. The request guard, ApiKey, resolves the key and logs which key made the request; the route handlers then call ApiKey::require with the scope needed for the requested action.
+
+As an alternative to sending the key itself, callers can sign each request with the "secret" of their registry entry. The SignedRequest guard checks the "x-key-id", "x-timestamp" and "x-signature" headers, where the signature is the hex encoded HMAC-SHA256 of "METHOD\nURI\nTIMESTAMP\n" followed by the raw body, URI being the path and query exactly as sent. Requests whose timestamp is outside the allowed window are rejected so a captured request cannot be replayed later. Because request guards cannot see the body, the handler finishes the check by calling verify_body once it has read the body, before it checks the scope of the caller, so a bad signature is always answered the same way. The Caller guard accepts either method, as well as the JWT bearer tokens checked by jwt.rs, and is what the routes use. Once a caller is authenticated, the guard counts the request against the caller's rate limit and the daily quota of the route (see limit.rs) and rejects it with 429 Too Many Requests when a limit is hit. A signed request is not authenticated until its body is checked, so the guard only checks its limits and verify_body counts it once the signature matches; otherwise anyone knowing a key id could use up the limits of that key with bad signatures.
 
 
 Variables:
     keys:
         A Vec of headers in the x-auth field of an incoming request.
 
-    key:
-        An Option that retrieves the value of the API_KEY environment variable.
+    KeyEntry:
+        A struct holding one registry entry: name, owner, key value, expiry date, allowed scopes, signing secret and rate limits.
+
+    KeyRegistry:
+        The list of all configured keys. It is loaded once at launch and handed to Rocket as managed state.
 
     ApiKeyError:
         An enum type that specifies the possible errors that can occur during the request guard check.
 
     ApiKey:
-         A struct that represents a valid API key.
+         A struct that represents a valid API key and the registry entry it belongs to.
+
+    SignatureError:
+        An enum type that specifies the possible errors that can occur while checking a signed request.
+
+    SignedRequest:
+        A struct holding the registry entry, signed message prefix and signature of a signed request, and the limiter charge the Caller guard left for verify_body.
+
+    Caller:
+        An enum that is either an ApiKey, a SignedRequest or a JwtBearer, depending on the headers of the request.
+
+Scopes:
+    up:read       - check a single target with /up, read /up/report
+    up:cycle      - check every target in up_targets with /up
+    scan:run      - scan a page with /scan
+    scan:read     - compare stored scans of a page with /scan/diff
+    crawl:run     - crawl a single site with /crawl and action "scan"
+    crawl:cycle   - crawl every target in crawl_targets with /crawl and action "cycle"
+    schedule:read - read the scheduled jobs with /schedule
+    jobs:read     - read the background jobs of every caller with /jobs, callers always see their own
+    audit:run     - audit the HTTPS and security headers of a single domain with /audit
+    audit:cycle   - audit the domain of every target in up_targets with /audit
+    admin         - grants every scope
 
 Functions:
+    KeyRegistry::load:
+        Reads the registry from the JSON file named by API_KEYS_FILE. If that variable is not set, the legacy API_KEY variable is turned into a single key named "default" with the admin scope.
+
     from_request:
-        This method is called by Rocket when it receives an incoming request. It checks the x-auth header and compares it to the API key in the environment. It then returns an Outcome that is either a ApiKey or an ApiKeyError.
+        This method is called by Rocket when it receives an incoming request. It looks up the x-auth header in the key registry, comparing keys in constant time, checks the expiry date and returns an Outcome that is either a ApiKey or an ApiKeyError.
+
+    require, require_scope:
+        Return a 403 Forbidden error if the caller does not hold the given scope (or admin).
+
+    Caller::name:
+        The name that identifies the caller, used for rate limits and to find the background jobs it started.
+
+    verify_body:
+        Checks the HMAC signature of a signed request against the body. Requests authorized with x-auth pass unchanged.
 
 
 
 Docker Vars:
+    API_KEYS_FILE:
+        Path to the JSON key registry, e.g.
+        [{"name": "scheduler", "owner": "ops@example.gov", "key": "...", "expires": "2024-12-31", "scopes": ["up:cycle", "crawl:cycle"],
+          "limits": {"per_minute": 30, "burst": 5, "daily": {"/crawl": 24}}}]
+
+    API_KEY:
+        Legacy single key, only used when API_KEYS_FILE is not set.
 
+    SIGNATURE_WINDOW:
+        Number of seconds a signed request's timestamp may differ from the server clock. Defaults to 300.
 
 
 Output:
@@ -42,10 +99,22 @@ Errors:
         Returned when there is no API key in the header.
 
     Invalid:
-        Returned when the API key in the header does not match the key in the environment.
+        Returned when the API key in the header does not match any key in the registry.
+
+    Expired:
+        Returned when the API key is past its expiry date.
 
     NotSet:
-        Returned when the API_KEY environment variable is not set.
+        Returned when no keys are configured.
+
+    SignatureError::UnknownKeyId:
+        Returned when the x-key-id header does not name a registry entry with a secret.
+
+    SignatureError::StaleTimestamp:
+        Returned when the x-timestamp header is not a unix timestamp inside the allowed window.
+
+    SignatureError::BadSignature:
+        Returned when the x-signature header does not match the request.
 
 
 */
@@ -55,25 +124,397 @@ pub enum ApiKeyError {
     BadCount, // too many keys
     Missing,  // no keys
     Invalid,  // invalid key
-    NotSet,   // environment variable not set
+    Expired,  // key past its expiry date
+    NotSet,   // no keys configured
+}
+
+// A single entry of the key registry file
+#[derive(Deserialize, Debug, Clone)]
+pub struct KeyEntry {
+    pub name: String,
+    pub owner: String,
+    key: String,
+    #[serde(default)]
+    expires: Option<NaiveDate>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    secret: Option<String>,
+    #[serde(default)]
+    limits: Option<Limits>,
 }
 
-pub struct ApiKey(String);
+impl KeyEntry {
+    // A key is valid up to and including its expiry date
+    fn is_expired(&self) -> bool {
+        match self.expires {
+            Some(expires) => Utc::now().date_naive() > expires,
+            None => false,
+        }
+    }
+
+    // Check that the key holds the scope needed for the requested action
+    fn require(&self, scope: &str) -> Result<(), status::Custom<String>> {
+        require_scope(&self.name, &self.scopes, scope)
+    }
+}
+
+// Check a list of granted scopes for the scope of an action, the admin scope grants every other scope
+pub fn require_scope(
+    name: &str,
+    scopes: &[String],
+    scope: &str,
+) -> Result<(), status::Custom<String>> {
+    if scopes.iter().any(|s| s == scope || s == "admin") {
+        Ok(())
+    } else {
+        Err(status::Custom(
+            Status::Forbidden,
+            format!("'{}' is missing scope '{}'", name, scope),
+        ))
+    }
+}
+
+pub struct KeyRegistry {
+    keys: Vec<KeyEntry>,
+}
+
+impl KeyRegistry {
+    // Load the key registry from API_KEYS_FILE, falling back to the legacy API_KEY variable
+    pub fn load() -> Result<Self, String> {
+        if let Ok(path) = get_env("API_KEYS_FILE") {
+            let file = std::fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read key registry {}: {}", path, e))?;
+            let keys: Vec<KeyEntry> = serde_json::from_str(&file)
+                .map_err(|e| format!("failed to parse key registry {}: {}", path, e))?;
+            return Ok(KeyRegistry { keys });
+        }
+        let keys = match get_env("API_KEY") {
+            Ok(key) => vec![KeyEntry {
+                name: "default".to_owned(),
+                owner: "API_KEY".to_owned(),
+                key,
+                expires: None,
+                scopes: vec!["admin".to_owned()],
+                secret: None,
+                limits: None,
+            }],
+            Err(_) => Vec::new(),
+        };
+        Ok(KeyRegistry { keys })
+    }
+
+    // Keys are compared in constant time, through their digests so the length of a key does not leak either
+    fn find(&self, key: &str) -> Option<&KeyEntry> {
+        let digest = Sha256::digest(key.as_bytes());
+        self.keys
+            .iter()
+            .find(|entry| memcmp::eq(&Sha256::digest(entry.key.as_bytes()), &digest))
+    }
+
+    // Signing keys are looked up by name and must have a secret
+    fn find_signing(&self, key_id: &str) -> Option<&KeyEntry> {
+        self.keys
+            .iter()
+            .find(|entry| entry.name == key_id && entry.secret.is_some())
+    }
+}
+
+pub struct ApiKey(KeyEntry);
 
 impl<'a, 'r> FromRequest<'a, 'r> for ApiKey {
     type Error = ApiKeyError;
 
-    // Check the x-auth header and compare to the API key in the environment
+    // Check the x-auth header and look it up in the key registry
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let registry = match request.guard::<State<KeyRegistry>>() {
+            Outcome::Success(registry) if !registry.keys.is_empty() => registry,
+            _ => return Outcome::Failure((Status::InternalServerError, ApiKeyError::NotSet)), // No keys configured
+        };
         let keys: Vec<_> = request.headers().get("x-auth").collect();
         match keys.len() {
             0 => Outcome::Failure((Status::Unauthorized, ApiKeyError::Missing)), // No API key found in header
-            1 => match get_env("API_KEY") {
-                Ok(key) if keys[0] == key => Outcome::Success(ApiKey(keys[0].to_string())), // Valid API key
-                Ok(_) => Outcome::Failure((Status::Unauthorized, ApiKeyError::Invalid)), // Invalid API key
-                Err(_) => Outcome::Failure((Status::InternalServerError, ApiKeyError::NotSet)), // Environment variable not set
+            1 => match registry.find(keys[0]) {
+                // Expired API key
+                Some(entry) if entry.is_expired() => {
+                    Outcome::Failure((Status::Unauthorized, ApiKeyError::Expired))
+                }
+                Some(entry) => {
+                    // Report which key made the request
+                    println!(
+                        "auth: {} {} by key '{}' ({})",
+                        request.method(),
+                        request.uri(),
+                        entry.name,
+                        entry.owner
+                    );
+                    Outcome::Success(ApiKey(entry.clone())) // Valid API key
+                }
+                None => Outcome::Failure((Status::Unauthorized, ApiKeyError::Invalid)), // Invalid API key
             },
             _ => Outcome::Failure((Status::BadRequest, ApiKeyError::BadCount)), // Multiple API keys found in header
         }
     }
 }
+
+#[derive(Debug)]
+pub enum SignatureError {
+    BadCount,       // a signature header was sent more than once
+    Missing,        // a signature header is missing
+    UnknownKeyId,   // no signing key with this id
+    StaleTimestamp, // timestamp unreadable or outside the window
+    BadSignature,   // signature does not match the request
+    Expired,        // key past its expiry date
+    NotSet,         // no keys configured
+}
+
+// The request a signed caller is counted for once its signature is verified:
+// the limiter, the caller's name and limits, the route and the usage of the request
+struct Charge(RateLimiter, String, Limits, String, RequestUsage);
+
+pub struct SignedRequest {
+    entry: KeyEntry,
+    message: Vec<u8>,
+    signature: Vec<u8>,
+    charge: Cell<Option<Charge>>,
+}
+
+impl SignedRequest {
+    // Finish the signature check once the handler has read the body, then count the request
+    pub fn verify_body(&self, body: &[u8]) -> Result<(), status::Custom<String>> {
+        // unwrap: find_signing only returns entries with a secret, and HMAC accepts keys of any length
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.entry.secret.as_ref().unwrap().as_bytes()).unwrap();
+        mac.update(&self.message);
+        mac.update(body);
+        mac.verify_slice(&self.signature).map_err(|_| {
+            status::Custom(
+                Status::Unauthorized,
+                format!(
+                    "{:?}: signature does not match request",
+                    SignatureError::BadSignature
+                ),
+            )
+        })?;
+
+        // The guard only checked the limits, the request is counted once
+        // and its usage replaces the checked one, so the response gets the same headers as other callers
+        if let Some(Charge(limiter, name, limits, route, request_usage)) = self.charge.take() {
+            let usage = limiter.check(&name, &limits, &route, true);
+            let rejected = usage.rejected;
+            request_usage.set(usage);
+            if let Some((e, retry_after)) = rejected {
+                println!("auth: {} rejected: {:?}", route, AuthError::Limited(e));
+                return Err(status::Custom(
+                    Status::TooManyRequests,
+                    format!("{:?}: retry after {} seconds", e, retry_after),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Get exactly one value of a signature header
+fn single_header<'a>(request: &'a Request, name: &str) -> Result<&'a str, SignatureError> {
+    let values: Vec<_> = request.headers().get(name).collect();
+    match values.len() {
+        0 => Err(SignatureError::Missing),
+        1 => Ok(values[0]),
+        _ => Err(SignatureError::BadCount),
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SignedRequest {
+    type Error = SignatureError;
+
+    // Check the key id and timestamp of a signed request, the body is checked by verify_body
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let registry = match request.guard::<State<KeyRegistry>>() {
+            Outcome::Success(registry) if !registry.keys.is_empty() => registry,
+            _ => return Outcome::Failure((Status::InternalServerError, SignatureError::NotSet)), // No keys configured
+        };
+        let headers = (
+            single_header(request, "x-key-id"),
+            single_header(request, "x-timestamp"),
+            single_header(request, "x-signature"),
+        );
+        let (key_id, timestamp, signature) = match headers {
+            (Ok(key_id), Ok(timestamp), Ok(signature)) => (key_id, timestamp, signature),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                return Outcome::Failure((Status::BadRequest, e)); // Missing or repeated header
+            }
+        };
+        let entry = match registry.find_signing(key_id) {
+            Some(entry) if entry.is_expired() => {
+                return Outcome::Failure((Status::Unauthorized, SignatureError::Expired));
+                // Expired key
+            }
+            Some(entry) => entry,
+            None => return Outcome::Failure((Status::Unauthorized, SignatureError::UnknownKeyId)), // Unknown key id
+        };
+
+        // Reject timestamps outside the window so old requests cannot be replayed
+        let window = get_env_or("SIGNATURE_WINDOW", 300);
+        match timestamp.parse::<i64>() {
+            Ok(ts) if (Utc::now().timestamp() - ts).abs() <= window => {}
+            _ => return Outcome::Failure((Status::Unauthorized, SignatureError::StaleTimestamp)), // Stale timestamp
+        }
+        let signature = match hex::decode(signature) {
+            Ok(signature) => signature,
+            Err(_) => {
+                return Outcome::Failure((Status::Unauthorized, SignatureError::BadSignature))
+            } // Not a hex signature
+        };
+
+        // Report which key made the request
+        println!(
+            "auth: {} {} signed by key '{}' ({})",
+            request.method(),
+            request.uri(),
+            entry.name,
+            entry.owner
+        );
+        let message = format!("{}\n{}\n{}\n", request.method(), request.uri(), timestamp);
+        Outcome::Success(SignedRequest {
+            entry: entry.clone(),
+            message: message.into_bytes(),
+            signature,
+            charge: Cell::new(None),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Key(ApiKeyError),
+    Signature(SignatureError),
+    Token(TokenError),
+    Limited(LimitError),
+}
+
+// A caller authorized by an x-auth key, a signed request or a bearer token
+pub enum Caller {
+    Key(ApiKey),
+    Signed(SignedRequest),
+    Bearer(JwtBearer),
+}
+
+impl Caller {
+    // Check that the caller holds the scope needed for the requested action
+    pub fn require(&self, scope: &str) -> Result<(), status::Custom<String>> {
+        match self {
+            Caller::Key(key) => key.0.require(scope),
+            Caller::Signed(signed) => signed.entry.require(scope),
+            Caller::Bearer(token) => token.require(scope),
+        }
+    }
+
+    // The name that identifies the caller, e.g. "key:scheduler" or "token:<subject>"
+    pub fn name(&self) -> String {
+        match self {
+            Caller::Key(ApiKey(entry)) | Caller::Signed(SignedRequest { entry, .. }) => {
+                format!("key:{}", entry.name)
+            }
+            Caller::Bearer(token) => format!("token:{}", token.subject()),
+        }
+    }
+
+    // The name the caller is rate limited by and its limits
+    fn limits(&self) -> (String, Limits) {
+        match self {
+            Caller::Key(ApiKey(entry)) | Caller::Signed(SignedRequest { entry, .. }) => (
+                self.name(),
+                entry.limits.clone().unwrap_or_else(Limits::default_limits),
+            ),
+            Caller::Bearer(_) => (self.name(), Limits::default_limits()),
+        }
+    }
+
+    // Check the signature against the body, other callers need no body check
+    pub fn verify_body(&self, body: &[u8]) -> Result<(), status::Custom<String>> {
+        match self {
+            Caller::Key(_) | Caller::Bearer(_) => Ok(()),
+            Caller::Signed(signed) => signed.verify_body(body),
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Caller {
+    type Error = AuthError;
+
+    // Use the signed request guard if a signature is sent, the bearer token guard if a
+    // bearer token is sent without an x-auth key, and the x-auth guard otherwise
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let bearer = request
+            .headers()
+            .get("Authorization")
+            .any(|value| value.starts_with("Bearer "));
+        let outcome = if request.headers().contains("x-signature") {
+            request
+                .guard::<SignedRequest>()
+                .map(Caller::Signed)
+                .map_failure(|(status, e)| (status, AuthError::Signature(e)))
+        } else if bearer && !request.headers().contains("x-auth") {
+            request
+                .guard::<JwtBearer>()
+                .map(Caller::Bearer)
+                .map_failure(|(status, e)| (status, AuthError::Token(e)))
+        } else {
+            request
+                .guard::<ApiKey>()
+                .map(Caller::Key)
+                .map_failure(|(status, e)| (status, AuthError::Key(e)))
+        };
+
+        // Count the request against the caller's rate limit and the route's daily quota,
+        // signed requests are only checked here and counted by verify_body
+        let outcome = match outcome {
+            Outcome::Success(caller) => match request.guard::<State<RateLimiter>>() {
+                Outcome::Success(limiter) => {
+                    let (name, limits) = caller.limits();
+                    let route = request
+                        .route()
+                        .map_or_else(|| request.uri().path(), |route| route.uri.path())
+                        .to_owned();
+                    let request_usage = request.local_cache(RequestUsage::default);
+                    let usage = match caller {
+                        Caller::Signed(ref signed) => {
+                            let usage = limiter.check(&name, &limits, &route, false);
+                            signed.charge.set(Some(Charge(
+                                limiter.inner().clone(),
+                                name,
+                                limits,
+                                route,
+                                request_usage.clone(),
+                            )));
+                            usage
+                        }
+                        _ => limiter.check(&name, &limits, &route, true),
+                    };
+                    let rejected = usage.rejected;
+                    request_usage.set(usage);
+                    match rejected {
+                        Some((e, _)) => {
+                            Outcome::Failure((Status::TooManyRequests, AuthError::Limited(e)))
+                        }
+                        None => Outcome::Success(caller),
+                    }
+                }
+                _ => Outcome::Success(caller),
+            },
+            outcome => outcome,
+        };
+
+        // Report why a request was rejected
+        if let Outcome::Failure((_, ref e)) = outcome {
+            println!(
+                "auth: {} {} rejected: {:?}",
+                request.method(),
+                request.uri(),
+                e
+            );
+        }
+        outcome
+    }
+}
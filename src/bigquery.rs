@@ -1,10 +1,17 @@
 use crate::crawl::CrawlData;
+use crate::diff::{ScanSummary, StoredIssue};
+use crate::fingerprint::fingerprint;
 use crate::get_env;
+use crate::page_auth::PageAuth;
+use crate::scan::ScanOptions;
+use crate::up::UpTarget;
 use gcp_bigquery_client;
 use sea_query;
+use sea_query::backend::QueryBuilder;
 use sea_query::types::Iden;
 use serde_json::Value as JsonValue;
 use std::boxed::Box;
+use std::collections::HashMap;
 
 use gcp_bigquery_client::Client;
 
@@ -12,9 +19,14 @@ use gcp_bigquery_client::Client;
 Code Summary:
 This module contains functions to retrieve data from and store data in Google BigQuery. The following functions are defined:
 
-read_up_targets: retrieves a list of URLs to be crawled from the up_targets table in the specified dataset
-read_crawl_targets: retrieves a list of crawl targets from the crawl_targets table in the specified dataset
+read_up_targets: retrieves the URLs to be checked and their assertions from the up_targets table in the specified dataset, and the rows that were rejected
+read_crawl_targets: retrieves a list of crawl targets from the crawl_targets table in the specified dataset, and the rows that were rejected
 store: stores a JSON object in the specified table in the specified dataset in Google BigQuery
+read_open_incidents: retrieves the start time of every incident that has not ended yet from the incidents table
+close_incident: sets the end time and duration of a target's open incident in the incidents table
+read_up_report: counts the checks, incidents, downtime and mean time to recovery per target over a time window from the ups and incidents tables, for report.rs to build its report from
+read_scan_summaries: retrieves the time, issue counts and accessScore of the scans of a URL from the crawls table
+read_scan_issues: retrieves the issues of given scans of a URL from the issues table
 
 
 API Key:
@@ -25,12 +37,22 @@ The GOOGLE_PROJECT_ID environment variable should be set to the ID of the Google
 
 // Implementation details:
 
-The read_up_targets function executes a query to retrieve a list of URLs to be crawled from the up_targets table in the specified dataset. The dataset_name parameter is the name of the dataset containing the table. The function returns a vector of strings representing the URLs.
+The read_up_targets function executes a query to retrieve the URLs to be checked from the up_targets table in the specified dataset. The dataset_name parameter is the name of the dataset containing the table. The function returns a vector of UpTarget structs with the URL and the assertions of each target, and the URL and reason of every row that was rejected because its expected_status could not be parsed or its body_regex is not a valid regular expression. A rejected row is skipped so the other targets are still checked. The assertion columns (expected_status, body_contains, body_regex, body_forbidden and max_response_ms) and the probe columns (probe_type, record_type and expected_answer) are optional, a table without them has HTTP targets without assertions. expected_status is a comma separated list of status codes.
 
-The read_crawl_targets function executes a query to retrieve a list of crawl targets from the crawl_targets table in the specified dataset. The dataset_name parameter is the name of the dataset containing the table. The function returns a vector of CrawlData structs representing the crawl targets.
+The read_crawl_targets function executes a query to retrieve a list of crawl targets from the crawl_targets table in the specified dataset. The dataset_name parameter is the name of the dataset containing the table. The function returns a vector of CrawlData structs representing the crawl targets. The optional standard, runners and ignore columns hold the ScanOptions of a target, runners and ignore as comma separated lists. The optional auth column holds the name of page credentials in PAGE_AUTH_FILE (see page_auth.rs). A target with an unsupported standard or runner, an unknown auth name or one that may not be sent to its url (see CrawlData::validate) is skipped, and returned with the reason next to the valid targets so the cycle can report it.
 
 The store function stores a JSON object in the specified table in the specified dataset in Google BigQuery. The dataset_name parameter is the name of the dataset containing the table, the table_name parameter is the name of the table to store the data in, and the object parameter is a reference to a JSON object to store. The function returns Ok(()) if the operation was successful, and an error message as a string if the operation failed.
 
+The read_open_incidents function queries the incidents table for rows without an ended_at. It returns a map from the target URL and probe type to the start time of their open incident, in milliseconds since the unix epoch. Rows stored before incidents had a probe_type are read as "http".
+
+The close_incident function updates the open incident of a target and probe type with the time it ended and its duration in seconds. The update is built with sea-query so the target is escaped.
+
+The read_up_report function aggregates the ups table (checks, checks that found the target up and checks that found it degraded) and the incidents table (incidents overlapping the window, the downtime inside the window and the mean duration of ended incidents) per target and joins the two into one UpReportRow per target. The start and end are timestamps formatted by the caller, the optional target is escaped with sea-query.
+
+The read_scan_summaries function reads the two latest scans of a URL from the crawls table, newest first. With a baseline it reads the latest scan and the scan stored at the baseline time instead. Only scans of one engine are read: the given engine, or the engine of the latest scan of the URL. Rows without an engine were stored before it was recorded and count as a11ywatch. A scan is identified by its last_scan_date cast to a string, the same value as last_scan_at in the issues table. The URL and baseline are escaped with sea-query.
+
+The read_scan_issues function reads the issues stored for a URL by an engine at the given scan times, each with the time of its scan, its fingerprint and whether it was suppressed. Rows stored without a fingerprint get it computed from their columns.
+
 The MyIden struct is an implementation of the Iden trait from the sea-query crate, which allows us to use custom identifiers when building SQL queries.
 
 The sea-query crate is used to construct SQL queries.
@@ -43,8 +65,10 @@ impl Iden for MyIden {
     }
 }
 
-// Retrieve a list of URLs to crawl
-pub async fn read_up_targets(dataset_name: String) -> Result<Vec<String>, String> {
+// Retrieve the URLs to check and their assertions
+pub async fn read_up_targets(
+    dataset_name: String,
+) -> Result<(Vec<UpTarget>, Vec<(String, String)>), String> {
     // Create a client to communicate with Google BigQuery
     let client = Client::from_service_account_key_file(&get_env("GOOGLE_APPLICATION_CREDENTIALS")?)
         .await
@@ -63,21 +87,59 @@ pub async fn read_up_targets(dataset_name: String) -> Result<Vec<String>, String
         .await
         .map_err(|e| format!("{}", e))?;
 
-    // Collect the URLs into a vector
-    let mut urls = Vec::new();
+    // Collect the targets into a vector, the assertion columns may be missing
+    let mut targets = Vec::new();
+    let mut invalid = Vec::new();
     while result_set.next_row() {
         if let Some(url) = result_set
             .get_string_by_name("url")
             .map_err(|e| format!("{}", e))?
         {
-            urls.push(url);
+            let optional = |name: &str| result_set.get_string_by_name(name).ok().flatten();
+            let expected_status = match optional("expected_status")
+                .map(|codes| {
+                    codes
+                        .split(',')
+                        .map(|code| code.trim().parse::<u16>())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()
+            {
+                Ok(codes) => codes.unwrap_or_default(),
+                Err(e) => {
+                    invalid.push((url, format!("invalid expected_status: {}", e)));
+                    continue;
+                }
+            };
+            let target = UpTarget {
+                expected_status,
+                body_contains: optional("body_contains"),
+                body_regex: optional("body_regex"),
+                body_forbidden: optional("body_forbidden"),
+                probe_type: optional("probe_type"),
+                record_type: optional("record_type"),
+                expected_answer: optional("expected_answer"),
+                max_response_ms: result_set
+                    .get_i64_by_name("max_response_ms")
+                    .ok()
+                    .flatten()
+                    .map(|ms| ms.max(0) as u64),
+                url,
+            };
+            // A target whose assertions can never pass is skipped
+            match target.validate() {
+                Ok(()) => targets.push(target),
+                Err(e) => invalid.push((target.url, e)),
+            }
         }
     }
-    Ok(urls)
+    Ok((targets, invalid))
 }
 
 // Retrieve a list of crawl targets
-pub async fn read_crawl_targets(dataset_name: String) -> Result<Vec<CrawlData>, String> {
+pub async fn read_crawl_targets(
+    dataset_name: String,
+) -> Result<(Vec<CrawlData>, Vec<(String, String)>), String> {
     // Create a client to communicate with Google BigQuery
     let client = Client::from_service_account_key_file(&get_env("GOOGLE_APPLICATION_CREDENTIALS")?)
         .await
@@ -98,6 +160,7 @@ pub async fn read_crawl_targets(dataset_name: String) -> Result<Vec<CrawlData>,
 
     // Collect the crawl targets into a vector
     let mut datapoints = Vec::new();
+    let mut invalid = Vec::new();
     while result_set.next_row() {
         let url = result_set
             .get_string_by_name("url")
@@ -124,14 +187,41 @@ pub async fn read_crawl_targets(dataset_name: String) -> Result<Vec<CrawlData>,
         if page_insights.is_none() {
             continue;
         }
-        datapoints.push(CrawlData {
+        // The standard, runners and ignore columns are optional, lists are comma separated
+        let optional = |name: &str| result_set.get_string_by_name(name).ok().flatten();
+        let list = |name: &str| {
+            optional(name).map(|list| {
+                list.split(',')
+                    .map(|item| item.trim().to_owned())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+        };
+        let options = ScanOptions {
+            standard: optional("standard"),
+            runners: list("runners"),
+            ignore: list("ignore"),
+        };
+
+        // The optional auth column names credentials of PAGE_AUTH_FILE, secrets are never kept here
+        let auth = optional("auth").map(PageAuth::Named);
+
+        let target = CrawlData {
             url: url.unwrap(),
             subdomains: subdomains.unwrap(),
             tld: tld.unwrap(),
             page_insights: page_insights.unwrap(),
-        })
+            options,
+            auth,
+        };
+
+        // A target with invalid options or credentials is skipped, the others are still crawled
+        match target.validate() {
+            Ok(()) => datapoints.push(target),
+            Err(e) => invalid.push((target.url, e.1)),
+        }
     }
-    Ok(datapoints)
+    Ok((datapoints, invalid))
 }
 
 // Store a JSON object in a BigQuery table
@@ -252,8 +342,8 @@ pub async fn store(
             query.into_table(table.clone());
             query.columns(columns.clone());
 
-            for set in chunk.into_iter() {
-                query.values(set.into_iter().map(|i| i.clone())).unwrap();
+            for set in chunk.iter() {
+                query.values(set.iter().cloned()).unwrap();
             }
 
             let query = query.to_string(sea_query::backend::MysqlQueryBuilder);
@@ -267,3 +357,367 @@ pub async fn store(
     }
     Ok(())
 }
+
+// Retrieve the start time of every open incident, by target and probe type
+pub async fn read_open_incidents(
+    dataset_name: String,
+) -> Result<HashMap<(String, String), i64>, String> {
+    // Create a client to communicate with Google BigQuery
+    let client = Client::from_service_account_key_file(&get_env("GOOGLE_APPLICATION_CREDENTIALS")?)
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    // Query the `incidents` table for incidents that have not ended
+    let mut result_set = client
+        .job()
+        .query(
+            &get_env("GOOGLE_PROJECT_ID")?,
+            gcp_bigquery_client::model::query_request::QueryRequest::new(format!(
+                "SELECT target, IFNULL(probe_type, 'http') AS probe_type, UNIX_MILLIS(started_at) AS started_ms FROM {}.incidents WHERE ended_at IS NULL",
+                dataset_name
+            )),
+        )
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    // Collect the start times into a map
+    let mut incidents = HashMap::new();
+    while result_set.next_row() {
+        let target = result_set
+            .get_string_by_name("target")
+            .map_err(|e| format!("invalid data from google big query, error: {}", e))?;
+        let probe_type = result_set
+            .get_string_by_name("probe_type")
+            .map_err(|e| format!("invalid data from google big query, error: {}", e))?;
+        let started_ms = result_set
+            .get_i64_by_name("started_ms")
+            .map_err(|e| format!("invalid data from google big query, error: {}", e))?;
+        if let (Some(target), Some(probe_type), Some(started_ms)) = (target, probe_type, started_ms)
+        {
+            incidents.insert((target, probe_type), started_ms);
+        }
+    }
+    Ok(incidents)
+}
+
+// Mark the open incident of a target and probe type as ended
+pub async fn close_incident(
+    dataset_name: String,
+    target: &str,
+    probe_type: &str,
+    ended_at: &str,
+    duration_seconds: i64,
+) -> Result<(), String> {
+    // Create a client to communicate with Google BigQuery
+    let client = Client::from_service_account_key_file(&get_env("GOOGLE_APPLICATION_CREDENTIALS")?)
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    // Define the table the incidents are stored in
+    let table = sea_query::types::TableRef::SchemaTable(
+        sea_query::types::SeaRc::new(MyIden(dataset_name)),
+        sea_query::types::SeaRc::new(MyIden("incidents".to_owned())),
+    );
+
+    let query = sea_query::Query::update()
+        .table(table)
+        .value(MyIden("ended_at".to_owned()), ended_at)
+        .value(MyIden("duration_seconds".to_owned()), duration_seconds)
+        .and_where(sea_query::Expr::col(MyIden("target".to_owned())).eq(target))
+        .and_where(
+            sea_query::Expr::col(MyIden("probe_type".to_owned()))
+                .if_null("http")
+                .eq(probe_type),
+        )
+        .and_where(sea_query::Expr::col(MyIden("ended_at".to_owned())).is_null())
+        .to_string(sea_query::backend::MysqlQueryBuilder);
+    let query = gcp_bigquery_client::model::query_request::QueryRequest::new(query);
+    client
+        .job()
+        .query(&get_env("GOOGLE_PROJECT_ID")?, query)
+        .await
+        .map_err(|e| format!("{}", e))?;
+    Ok(())
+}
+
+// The counts of one target over the window of an up report
+pub struct UpReportRow {
+    pub target: String,
+    pub checks: i64,
+    pub up_checks: i64,
+    pub degraded_checks: i64,
+    pub incidents: i64,
+    pub downtime_seconds: i64,
+    pub mttr_seconds: Option<f64>,
+}
+
+// Count the checks and incidents of every target between start and end
+pub async fn read_up_report(
+    dataset_name: String,
+    start: &str,
+    end: &str,
+    target: Option<&str>,
+) -> Result<Vec<UpReportRow>, String> {
+    // Create a client to communicate with Google BigQuery
+    let client = Client::from_service_account_key_file(&get_env("GOOGLE_APPLICATION_CREDENTIALS")?)
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    // Only report on one target if it is given, escaping the URL
+    let filter = match target {
+        Some(target) => format!(
+            "AND target = {}",
+            sea_query::backend::MysqlQueryBuilder.value_to_string(
+                &sea_query::value::Value::String(Some(Box::new(target.to_owned())))
+            )
+        ),
+        None => String::new(),
+    };
+
+    // Aggregate the checks and the incidents overlapping the window per target
+    let query = format!(
+        "WITH checks AS (
+            SELECT target, COUNT(*) AS checks, COUNTIF(status) AS up_checks,
+                COUNTIF(state = 'degraded') AS degraded_checks
+            FROM {dataset}.ups
+            WHERE probed_at >= TIMESTAMP('{start}') AND probed_at < TIMESTAMP('{end}') {filter}
+            GROUP BY target
+        ), outages AS (
+            SELECT target, COUNT(*) AS incidents,
+                SUM(TIMESTAMP_DIFF(
+                    LEAST(IFNULL(ended_at, TIMESTAMP('{end}')), TIMESTAMP('{end}')),
+                    GREATEST(started_at, TIMESTAMP('{start}')),
+                    SECOND
+                )) AS downtime_seconds,
+                AVG(duration_seconds) AS mttr_seconds
+            FROM {dataset}.incidents
+            WHERE started_at < TIMESTAMP('{end}')
+                AND (ended_at IS NULL OR ended_at >= TIMESTAMP('{start}')) {filter}
+            GROUP BY target
+        )
+        SELECT COALESCE(checks.target, outages.target) AS target,
+            IFNULL(checks.checks, 0) AS checks,
+            IFNULL(checks.up_checks, 0) AS up_checks,
+            IFNULL(checks.degraded_checks, 0) AS degraded_checks,
+            IFNULL(outages.incidents, 0) AS incidents,
+            IFNULL(outages.downtime_seconds, 0) AS downtime_seconds,
+            outages.mttr_seconds AS mttr_seconds
+        FROM checks FULL OUTER JOIN outages ON checks.target = outages.target
+        ORDER BY target",
+        dataset = dataset_name,
+        start = start,
+        end = end,
+        filter = filter,
+    );
+    let mut result_set = client
+        .job()
+        .query(
+            &get_env("GOOGLE_PROJECT_ID")?,
+            gcp_bigquery_client::model::query_request::QueryRequest::new(query),
+        )
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    // Collect the counts of each target
+    let mut rows = Vec::new();
+    while result_set.next_row() {
+        let invalid = |e| format!("invalid data from google big query, error: {}", e);
+        let target = match result_set.get_string_by_name("target").map_err(invalid)? {
+            Some(target) => target,
+            None => continue,
+        };
+        rows.push(UpReportRow {
+            target,
+            checks: result_set
+                .get_i64_by_name("checks")
+                .map_err(invalid)?
+                .unwrap_or(0),
+            up_checks: result_set
+                .get_i64_by_name("up_checks")
+                .map_err(invalid)?
+                .unwrap_or(0),
+            degraded_checks: result_set
+                .get_i64_by_name("degraded_checks")
+                .map_err(invalid)?
+                .unwrap_or(0),
+            incidents: result_set
+                .get_i64_by_name("incidents")
+                .map_err(invalid)?
+                .unwrap_or(0),
+            downtime_seconds: result_set
+                .get_i64_by_name("downtime_seconds")
+                .map_err(invalid)?
+                .unwrap_or(0),
+            mttr_seconds: result_set
+                .get_f64_by_name("mttr_seconds")
+                .map_err(invalid)?,
+        });
+    }
+    Ok(rows)
+}
+
+// Escape a string for a query
+fn quote(value: &str) -> String {
+    sea_query::backend::MysqlQueryBuilder.value_to_string(&sea_query::value::Value::String(Some(
+        Box::new(value.to_owned()),
+    )))
+}
+
+pub async fn read_scan_summaries(
+    dataset_name: String,
+    url: &str,
+    baseline: Option<&str>,
+    engine: Option<&str>,
+) -> Result<Vec<ScanSummary>, String> {
+    // Create a client to communicate with Google BigQuery
+    let client = Client::from_service_account_key_file(&get_env("GOOGLE_APPLICATION_CREDENTIALS")?)
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    // Only scans of one engine are compared, the given one or that of the latest scan.
+    // Rows stored before the engine was recorded are A11yWatch scans
+    let engine = match engine {
+        Some(engine) => quote(engine),
+        None => "(SELECT engine FROM scans ORDER BY last_scan_date DESC LIMIT 1)".to_owned(),
+    };
+
+    // The two latest scans, or the latest scan and the baseline
+    let (filter, limit) = match baseline {
+        Some(baseline) => (
+            format!(
+                "AND (scanned_at = {baseline}
+                    OR last_scan_date = (SELECT MAX(last_scan_date) FROM engine_scans))",
+                baseline = quote(baseline),
+            ),
+            "",
+        ),
+        None => (String::new(), "LIMIT 2"),
+    };
+    let query = format!(
+        "WITH scans AS (
+            SELECT CAST(last_scan_date AS STRING) AS scanned_at, last_scan_date,
+                IFNULL(engine, 'a11ywatch') AS engine,
+                issues_error, issues_warning, issues_notice, access_score
+            FROM {dataset}.crawls
+            WHERE url = {url}
+        ), engine_scans AS (
+            SELECT * FROM scans WHERE engine = {engine}
+        )
+        SELECT DISTINCT scanned_at, engine, issues_error, issues_warning, issues_notice, access_score
+        FROM engine_scans
+        WHERE TRUE {filter}
+        ORDER BY scanned_at DESC {limit}",
+        dataset = dataset_name,
+        url = quote(url),
+        engine = engine,
+        filter = filter,
+        limit = limit,
+    );
+    let mut result_set = client
+        .job()
+        .query(
+            &get_env("GOOGLE_PROJECT_ID")?,
+            gcp_bigquery_client::model::query_request::QueryRequest::new(query),
+        )
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    let mut scans = Vec::new();
+    while result_set.next_row() {
+        let invalid = |e| format!("invalid data from google big query, error: {}", e);
+        let scanned_at = match result_set
+            .get_string_by_name("scanned_at")
+            .map_err(invalid)?
+        {
+            Some(scanned_at) => scanned_at,
+            None => continue,
+        };
+        scans.push(ScanSummary {
+            scanned_at,
+            engine: result_set
+                .get_string_by_name("engine")
+                .map_err(invalid)?
+                .unwrap_or_default(),
+            errors: result_set
+                .get_i64_by_name("issues_error")
+                .map_err(invalid)?
+                .unwrap_or(0),
+            warnings: result_set
+                .get_i64_by_name("issues_warning")
+                .map_err(invalid)?
+                .unwrap_or(0),
+            notices: result_set
+                .get_i64_by_name("issues_notice")
+                .map_err(invalid)?
+                .unwrap_or(0),
+            access_score: result_set
+                .get_f64_by_name("access_score")
+                .map_err(invalid)?,
+        });
+    }
+    Ok(scans)
+}
+
+pub async fn read_scan_issues(
+    dataset_name: String,
+    url: &str,
+    engine: &str,
+    scanned_at: &[&str],
+) -> Result<Vec<StoredIssue>, String> {
+    // Create a client to communicate with Google BigQuery
+    let client = Client::from_service_account_key_file(&get_env("GOOGLE_APPLICATION_CREDENTIALS")?)
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    let scanned_at: Vec<String> = scanned_at.iter().map(|time| quote(time)).collect();
+    let query = format!(
+        "SELECT CAST(last_scan_at AS STRING) AS scanned_at, fingerprint, issue_code, type, selector, context, message,
+            IFNULL(suppressed, FALSE) AS suppressed
+        FROM {dataset}.issues
+        WHERE url = {url} AND IFNULL(engine, 'a11ywatch') = {engine}
+            AND CAST(last_scan_at AS STRING) IN ({scanned_at})",
+        dataset = dataset_name,
+        url = quote(url),
+        engine = quote(engine),
+        scanned_at = scanned_at.join(", "),
+    );
+    let mut result_set = client
+        .job()
+        .query(
+            &get_env("GOOGLE_PROJECT_ID")?,
+            gcp_bigquery_client::model::query_request::QueryRequest::new(query),
+        )
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    let mut issues = Vec::new();
+    while result_set.next_row() {
+        let invalid = |e| format!("invalid data from google big query, error: {}", e);
+        let column = |name: &str| {
+            result_set
+                .get_string_by_name(name)
+                .map(|value| value.unwrap_or_default())
+                .map_err(invalid)
+        };
+        let mut issue = StoredIssue {
+            scanned_at: column("scanned_at")?,
+            fingerprint: column("fingerprint")?,
+            code: column("issue_code")?,
+            issue_type: column("type")?,
+            selector: column("selector")?,
+            context: column("context")?,
+            message: column("message")?,
+            suppressed: result_set
+                .get_bool_by_name("suppressed")
+                .map_err(invalid)?
+                .unwrap_or(false),
+        };
+
+        // Rows stored before fingerprints were added get theirs computed
+        if issue.fingerprint.is_empty() {
+            issue.fingerprint = fingerprint(url, &issue.code, &issue.selector, &issue.context);
+        }
+        issues.push(issue);
+    }
+    Ok(issues)
+}
@@ -0,0 +1,119 @@
+use crate::get_env_or;
+use crate::scan::ScanData;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/*
+Code Summary:
+This module caches the results of /scan, so dashboards and people that scan the same URL again within minutes do not send another request to A11yWatch or add duplicate rows to the issues and crawls tables. Results are kept in memory for SCAN_CACHE_TTL seconds, keyed on the URL, page_insights and the standard, runners and ignore options, so a scan with other options is never answered with a cached result. A request with "force": true always scans and refreshes the cache. Scans with page credentials (see page_auth.rs) are never cached, so a page seen with one login is not served to another caller. With the cache on, every result is marked with "cached" and the "scanned_at" time of the scan that produced it.
+
+Variables:
+    Entry :
+        A cached result and the time it was scanned.
+    CACHE :
+        The cached results by key, shared by every request.
+
+Functions:
+    lookup :
+        Returns the marked result of a fresh entry for the scan, None if the cache is off, the scan is forced or has page credentials, or there is no fresh entry.
+    insert :
+        Caches the result of a scan that was just stored, unless it has page credentials, drops the expired entries and returns the marked result.
+    key :
+        The cache key of a scan, the JSON encoding of its URL and options.
+    mark :
+        Adds "cached" and "scanned_at" to a result.
+
+Docker Vars:
+    SCAN_CACHE_TTL :
+        Seconds a scan result is served from the cache, default 0 which turns the cache off.
+
+Output:
+    The mapped scan result with "cached": true or false and "scanned_at", e.g. "2023-02-01 12:00:00 UTC".
+
+Errors:
+    None
+*/
+
+struct Entry {
+    scanned_at: DateTime<Utc>,
+    result: JsonValue,
+}
+
+static CACHE: Mutex<Option<HashMap<String, Entry>>> = Mutex::new(None);
+
+// The seconds results are cached for, 0 when the cache is off
+fn ttl() -> i64 {
+    get_env_or("SCAN_CACHE_TTL", 0)
+}
+
+// Key a scan on its URL and every option that changes the result, encoded as JSON so no
+// value can run into the next
+fn key(data: &ScanData) -> String {
+    let sorted = |list: &Option<Vec<String>>| {
+        list.clone().map(|mut list| {
+            list.sort();
+            list
+        })
+    };
+    serde_json::to_string(&(
+        &data.url,
+        data.page_insights,
+        &data.options.standard,
+        sorted(&data.options.runners),
+        sorted(&data.options.ignore),
+    ))
+    .unwrap()
+}
+
+// Mark a result as served from the cache or not, with the time it was scanned
+fn mark(result: &JsonValue, cached: bool, scanned_at: DateTime<Utc>) -> JsonValue {
+    let mut result = result.clone();
+    if let Some(object) = result.as_object_mut() {
+        object.insert("cached".to_owned(), JsonValue::Bool(cached));
+        object.insert(
+            "scanned_at".to_owned(),
+            JsonValue::String(scanned_at.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+        );
+    }
+    result
+}
+
+// Return the cached result of the scan if it is still fresh and the scan is not forced
+pub fn lookup(data: &ScanData) -> Option<JsonValue> {
+    let ttl = ttl();
+    if ttl <= 0 || data.force || data.auth.is_some() {
+        return None;
+    }
+    let cache = CACHE.lock().unwrap();
+    let entry = cache.as_ref()?.get(&key(data))?;
+    if Utc::now() - entry.scanned_at >= Duration::seconds(ttl) {
+        return None;
+    }
+    Some(mark(&entry.result, true, entry.scanned_at))
+}
+
+// Cache the result of a scan and return it marked as fresh
+pub fn insert(data: &ScanData, result: JsonValue) -> JsonValue {
+    let ttl = ttl();
+    if ttl <= 0 {
+        return result;
+    }
+    let now = Utc::now();
+    let marked = mark(&result, false, now);
+    if data.auth.is_some() {
+        return marked;
+    }
+    let mut cache = CACHE.lock().unwrap();
+    let cache = cache.get_or_insert_with(HashMap::new);
+    cache.retain(|_, entry| now - entry.scanned_at < Duration::seconds(ttl));
+    cache.insert(
+        key(data),
+        Entry {
+            scanned_at: now,
+            result,
+        },
+    );
+    marked
+}
@@ -1,18 +1,19 @@
 use reqwest::Client;
-use rocket::{http::Status, post, response::status, Data};
+use rocket::{http::Status, post, response::status, Data, State};
 use serde::de::IntoDeserializer;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value as JsonValue;
-use std::io::{BufReader, Read};
-use std::time::Duration;
+use std::io::Read;
 
-use crate::auth::ApiKey;
+use crate::a11ywatch::A11yError;
+use crate::auth::Caller;
 use crate::bigquery;
 use crate::bigquery::store as bq_store;
-use crate::get_env;
-use crate::map_json::JsonMapper;
-use crate::util::check_for_error;
+use crate::jobs::{self, Job, JobRegistry};
+use crate::page_auth::PageAuth;
+use crate::scan::ScanOptions;
+use crate::scanner::{map_pages, A11yWatch, Mapped, ScanError, Scanner};
 
 /*
 Code Summary
@@ -21,14 +22,18 @@ This Rust code defines a Rocket endpoint and supporting functions for running a
 
 
 Variables
-    CrawlData struct: defines the fields of the JSON body data for a single audit URL.
+    CrawlData struct: defines the fields of the JSON body data for a single audit URL, including the optional standard, runners and ignore of ScanOptions (see scan.rs). The options are forwarded to A11yWatch and recorded with the stored rows. The optional auth holds page credentials (see page_auth.rs) that are forwarded to A11yWatch but never stored. CrawlData::validate rejects unsupported options, credentials that may not be sent to the url, and named credentials with subdomains or tld, since such a crawl would leave the hosts of the credentials.
 
-    catch_crawl function: Rocket endpoint for the /crawl route. Handles the incoming HTTP request, checks the action, and calls run_crawl() with the given data.
+    catch_crawl function: Rocket endpoint for the /crawl route. Handles the incoming HTTP request, checks the signature of signed requests, the action and the scope of the caller ("crawl:run" for scan, "crawl:cycle" for cycle), and calls run_crawl() with the given data. With "async": true in the body, the crawl or cycle runs as a background job and the endpoint answers 202 Accepted with the job id (see jobs.rs).
 
-    run_crawl function: performs the web crawl by sending an HTTP POST request to an external API and handling the response. The data from the response is then mapped to the appropriate format and stored in BigQuery.
+    run_cycle function: fetches the crawl targets from BigQuery and calls run_crawl() on each of them. It is used by the cycle action and by the scheduler (see schedule.rs).
+
+    cycle function: the crawl cycle behind run_cycle. Run as a job, it reports its progress and records the error of a target that fails instead of stopping the cycle. Rows of crawl_targets with an invalid standard, runner or auth name are skipped in either case, recorded as target errors of the job or logged without one.
+
+    run_crawl function: performs the web crawl by sending an HTTP POST request to the A11yWatch API through the A11yWatch scanner (see scanner.rs) and reading the pages of the response. The pages are then mapped to the appropriate format by scanner::map_pages, the issues rows get their fingerprint (see fingerprint.rs) and are flagged if a suppression rule matches them (see suppress.rs), and the rows are stored in BigQuery.
 
 Functions
-    check_for_error: checks the response data for any error messages and throws an error if any are found.
+    check_for_error: checks the response data for any error messages and throws an error if any are found. Called by the A11yWatch scanner.
 
     bq_store: stores the response data in a Google BigQuery table.
 
@@ -36,12 +41,12 @@ Docker Vars
     None
 
 Output
-    catch_crawl returns a JSON string with the response data from run_crawl.
+    catch_crawl returns a JSON string with the response data from run_crawl, or 202 Accepted with {"id", "status", "status_url"} for a background job.
 
     run_crawl returns a Result<JsonValue, rocket::response::status::Custom<std::string::String>> with the mapped response data or an error message.
 
 Error Messages
-    - If an error occurs parsing the request body data, catch_crawl returns a BadRequest error message.
+    - If an error occurs parsing the request body data, or the standard or a runner is not supported, catch_crawl returns a BadRequest error message.
     - If an error occurs sending the HTTP request or parsing the response data, run_crawl returns an InternalServerError error message.
     - If an error occurs applying the JSON mappings, run_crawl returns an InternalServerError error message.
     - If an error occurs storing the data in BigQuery, run_crawl returns an InternalServerError error message.
@@ -56,6 +61,31 @@ pub struct CrawlData {
     pub subdomains: bool,
     pub tld: bool,
     pub page_insights: bool,
+    #[serde(flatten)]
+    pub options: ScanOptions,
+    #[serde(default, skip_serializing)]
+    pub auth: Option<PageAuth>,
+}
+
+impl CrawlData {
+    // Reject unsupported options and unusable credentials before anything is sent to A11yWatch
+    pub fn validate(&self) -> Result<(), rocket::response::status::Custom<std::string::String>> {
+        self.options.validate()?;
+        if let Some(auth) = &self.auth {
+            auth.validate(&self.url)?;
+            // Named credentials are only sent to their hosts, which a crawl of other domains would leave
+            if let (PageAuth::Named(name), true) = (auth, self.subdomains || self.tld) {
+                return Err(status::Custom(
+                    Status::BadRequest,
+                    format!(
+                        "page credentials '{}' cannot be used to crawl subdomains or other top level domains",
+                        name
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 // The endpoint for the `catch_crawl` function is `/crawl` with the HTTP method POST.
@@ -63,24 +93,45 @@ pub struct CrawlData {
 #[post("/crawl", data = "<raw_data>")]
 pub fn catch_crawl(
     raw_data: Data,
-    _key: ApiKey,
+    registry: State<JobRegistry>,
+    caller: Caller,
 ) -> Result<
-    rocket::response::content::Json<String>,
+    status::Custom<rocket::response::content::Json<String>>,
     rocket::response::status::Custom<std::string::String>,
 > {
+    // Read the request body, limited to 1 MB, and check the signature of signed requests
+    let mut buf = Vec::new();
+    raw_data
+        .open()
+        .take(1024 * 1024)
+        .read_to_end(&mut buf)
+        .map_err(|e| {
+            status::Custom(
+                Status::BadRequest,
+                format!("Request Error Failed to read body data: {}", e),
+            )
+        })?;
+    caller.verify_body(&buf)?;
+
     // Parse the JSON data from the request body into a Map
-    let data: serde_json::Map<String, JsonValue> = serde_json::from_reader(BufReader::new(
-        raw_data.open().take(1024 * 1024),
-    ))
-    .map_err(|e| {
+    let data: serde_json::Map<String, JsonValue> = serde_json::from_slice(&buf).map_err(|e| {
         status::Custom(
             Status::BadRequest,
             format!("Request Error Failed to parse body data: {}", e),
         )
     })?;
-    let rt = tokio::runtime::Runtime::new().unwrap();
-    match data.get("action").map(|v| v.as_str()).flatten() {
+    // With "async": true the crawl runs as a background job (see jobs.rs)
+    let run_async = jobs::wants_async(&data);
+    let respond = |body: String| status::Custom(Status::Ok, rocket::response::content::Json(body));
+    let accepted = |job: &Job| {
+        status::Custom(
+            Status::Accepted,
+            rocket::response::content::Json(jobs::accepted(job)),
+        )
+    };
+    match data.get("action").and_then(|v| v.as_str()) {
         Some("scan") => {
+            caller.require("crawl:run")?;
             // If the action is `scan`, deserialize the JSON data into a CrawlData struct
             let data = CrawlData::deserialize(JsonValue::Object(data).into_deserializer())
                 .map_err(|e| {
@@ -89,34 +140,27 @@ pub fn catch_crawl(
                         format!("Request Error Failed to parse body data: {}", e),
                     )
                 })?;
+            data.validate()?;
+            if run_async {
+                let job = registry.spawn(&caller, "crawl", Some(data.url.clone()), 1, |_| {
+                    run_crawl(data)
+                })?;
+                return Ok(accepted(&job));
+            }
             // Run the crawl and return the response as a JSON string
-            Ok(rocket::response::content::Json(
-                run_crawl(data)?.to_string(),
-            ))
+            Ok(respond(run_crawl(data)?.to_string()))
         }
 
         // Cycle through crawl_targets
         Some("cycle") => {
             // If the action is `cycle`, fetch all crawl targets from Google BigQuery,
             // run the crawl on each target, and return an array of responses as a JSON string
-            let targets = rt
-                .block_on(bigquery::read_crawl_targets("rusty_a11y".to_owned()))
-                .map_err(|e| {
-                    status::Custom(
-                        Status::InternalServerError,
-                        format!(
-                            "Database Error Error fetching crawl targets from google big query: {}",
-                            e
-                        ),
-                    )
-                })?;
-            let mut responses = Vec::new();
-            for target in targets {
-                responses.push(run_crawl(target)?);
+            caller.require("crawl:cycle")?;
+            if run_async {
+                let job = registry.spawn(&caller, "crawl", None, 0, |job| cycle(Some(job)))?;
+                return Ok(accepted(&job));
             }
-            Ok(rocket::response::content::Json(
-                serde_json::Value::Array(responses).to_string(),
-            ))
+            Ok(respond(run_cycle()?.to_string()))
         }
         Some(_) | None => Err(status::Custom(
             Status::BadRequest,
@@ -125,6 +169,53 @@ pub fn catch_crawl(
     }
 }
 
+// Fetches all crawl targets from Google BigQuery and runs the crawl on each of them
+pub fn run_cycle() -> Result<JsonValue, rocket::response::status::Custom<std::string::String>> {
+    cycle(None)
+}
+
+// Runs the crawl cycle. As a job, a failed target is recorded and the cycle goes on with the others
+fn cycle(
+    job: Option<&Job>,
+) -> Result<JsonValue, rocket::response::status::Custom<std::string::String>> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (targets, invalid) = rt
+        .block_on(bigquery::read_crawl_targets("rusty_a11y".to_owned()))
+        .map_err(|e| {
+            status::Custom(
+                Status::InternalServerError,
+                format!(
+                    "Database Error Error fetching crawl targets from google big query: {}",
+                    e
+                ),
+            )
+        })?;
+    let mut responses = Vec::new();
+    if let Some(job) = job {
+        job.set_total(targets.len());
+    }
+
+    // Invalid rows were skipped, the other targets are still crawled
+    for (url, error) in &invalid {
+        match job {
+            Some(job) => job.target_error(url, error),
+            None => println!("crawl: skipped invalid crawl target {}: {}", url, error),
+        }
+    }
+    for target in targets {
+        let url = target.url.clone();
+        match (run_crawl(target), job) {
+            (Ok(response), _) => responses.push(response),
+            (Err(e), Some(job)) => job.target_error(&url, &e.1),
+            (Err(e), None) => return Err(e),
+        }
+        if let Some(job) = job {
+            job.advance();
+        }
+    }
+    Ok(serde_json::Value::Array(responses))
+}
+
 // Sends the crawl data to an API and maps the response data before storing it in Google BigQuery
 fn run_crawl(
     data: CrawlData,
@@ -132,68 +223,61 @@ fn run_crawl(
     // Create a new reqwest client for sending HTTP requests
     let client = Client::new();
     // Creating the json data for the request
-    let json_data = json!({
+    let mut json_data = json!({
         "url": data.url,
         "subdomains": data.subdomains,
         "tld": data.tld,
         "pageInsights": data.page_insights,
     });
+    data.options.apply(&mut json_data);
+    if let Some(auth) = &data.auth {
+        auth.apply(&data.url, &mut json_data)?;
+    }
 
     // Create a new tokio runtime so we can send an asynchronous request
     let rt = tokio::runtime::Runtime::new().unwrap();
     let _guard = rt.enter();
 
-    // Send the request and parse the response
-    let future = rt
-        .block_on(
-            client
-                .post(format!(
-                    "{}/crawl",
-                    get_env("A11Y_URL")
-                        .map_err(|e| { status::Custom(Status::InternalServerError, e,) })?
-                ))
-                .header("Accept", "application/json")
-                .header("Content-Type", "application/json")
-                .header(
-                    "Authorization",
-                    get_env("A11Y_JWT")
-                        .map_err(|e| status::Custom(Status::InternalServerError, e))?,
-                )
-                .body(json_data.to_string())
-                .timeout(Duration::from_secs(600)) // Wait up to 15 minutes
-                .send(),
-        )
-        .map_err(|e| {
-            // If there was an error sending the request, return an internal server error with the error message
-            status::Custom(
-                Status::InternalServerError,
-                format!("Request Error Problem sending request: {}", e),
-            )
-        })?
-        .json::<JsonValue>();
-
-    // If there was an error parsing the response, return an internal server error with the error message
-    let response = rt.block_on(future).map_err(|e| {
-        status::Custom(
+    // Send the request to A11yWatch and read the pages of the response (see scanner.rs)
+    let scanner = A11yWatch {
+        endpoint: "crawl",
+        body: json_data,
+    };
+    let pages = rt.block_on(scanner.pages(&client)).map_err(|e| match e {
+        ScanError::A11y(A11yError::Config(e)) => status::Custom(Status::InternalServerError, e),
+        ScanError::A11y(A11yError::Login(e)) => status::Custom(
+            Status::InternalServerError,
+            format!("Request Error Problem logging in to A11yWatch: {}", e),
+        ),
+        ScanError::A11y(A11yError::Unauthorized) => status::Custom(
+            Status::InternalServerError,
+            "Request Error A11yWatch rejected the JWT".to_owned(),
+        ),
+        // If there was an error sending the request, return an internal server error with the error message
+        ScanError::A11y(A11yError::Request(e)) => status::Custom(
+            Status::InternalServerError,
+            format!("Request Error Problem sending request: {}", e),
+        ),
+        // If there was an error parsing the response, return an internal server error with the error message
+        ScanError::A11y(A11yError::Response(e)) => status::Custom(
             Status::InternalServerError,
             format!("Response Error Problem parsing response: {}", e),
-        )
+        ),
+        // If there was an error in the response, return it
+        ScanError::Failed(e) => e,
+        ScanError::Invalid(e) => status::Custom(
+            Status::InternalServerError,
+            format!("Response Error Problem parsing response: {}", e),
+        ),
     })?;
 
-    // Check if there was an error in the response and return an error if there was
-    check_for_error(&response)?;
-
-    // Apply json mappings and upload to google big query
-    // Load the mapping files for converting the JSON response to the format we need to store in BigQuery
-    let mapper_bq_issues =
-        JsonMapper::new(serde_json::from_str(include_str!("../mapping/bq_issues.json")).unwrap());
-    let mapper_bq =
-        JsonMapper::new(serde_json::from_str(include_str!("../mapping/bq_crawls.json")).unwrap());
-    let mapper =
-        JsonMapper::new(serde_json::from_str(include_str!("../mapping/crawls.json")).unwrap());
-
-    // Apply the mappings to the response JSON to get the format we need for the BigQuery tables
-    let result_bq_issues = mapper_bq_issues.map(&response).map_err(|e| {
+    // Apply the json mappings to get the format we need for the BigQuery tables and the client.
+    // The issues rows get their fingerprint and suppressed issues are flagged and counted per page
+    let Mapped {
+        issues: result_bq_issues,
+        crawls: result_bq,
+        result,
+    } = map_pages(scanner.engine(), &pages, true, &data.options).map_err(|e| {
         // If there was an error applying the mapping, return an internal server error with the error message
         status::Custom(
             Status::InternalServerError,
@@ -214,13 +298,6 @@ fn run_crawl(
         )
     })?;
 
-    let result_bq = mapper_bq.map(&response).map_err(|e| {
-        status::Custom(
-            Status::InternalServerError,
-            format!("Mapping Error Failed to map json data: {:?}", e),
-        )
-    })?;
-
     // Store the data in the BigQuery table for crawls
     rt.block_on(bq_store(
         "rusty_a11y".to_owned(), // BigQuery Dataset
@@ -235,10 +312,5 @@ fn run_crawl(
     })?;
 
     // Return the mapped response data
-    Ok(mapper.map(&response).map_err(|e| {
-        status::Custom(
-            Status::InternalServerError,
-            format!("Mapping Error Failed to map json data: {:?}", e),
-        )
-    })?)
+    Ok(result)
 }
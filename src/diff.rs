@@ -0,0 +1,292 @@
+use crate::auth::Caller;
+use crate::bigquery::{read_scan_issues, read_scan_summaries};
+use rocket::get;
+use rocket::{http::Status, response::status};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/*
+Code Summary:
+This module compares two scans of a page stored by /scan and /crawl, so a team that ships a fix sees what changed instead of re-reading every issue. By default the latest scan of the URL is compared to the one before it; a baseline picks an earlier scan instead. Only scans of one engine are compared, the engine of the latest scan unless engine is given, so a pa11y or axe report imported between two A11yWatch scans is not compared with them. Issues of the latest scan that the baseline does not have are new, issues of the baseline that are gone are fixed, and issues found by both are persisting. An issue is the same in both scans if it has the same fingerprint (see fingerprint.rs), so generated class names and session ids in its selector or context do not make it new. Issues matched by a suppression rule (see suppress.rs) are listed with suppressed set to true, and are not part of the counts of the crawls rows the delta is computed from.
+
+Variables:
+    caller :
+        The API key, signed request or bearer token that made the request. It needs the "scan:read" scope.
+    StoredIssue :
+        One issue of a stored scan, read from the issues table.
+    ScanSummary :
+        The time, engine, the error, warning and notice counts and accessScore of a stored scan, read from the crawls table.
+    Delta :
+        The change of the counts and accessScore from the baseline to the latest scan.
+    Diff :
+        The two scans, the Delta and the classified issues.
+
+Functions:
+    catch_scan_diff :
+        Rocket endpoint for GET /scan/diff. Reads the url, optional baseline and optional engine from the query string and returns the Diff as JSON.
+    classify :
+        Sorts the issues of the two scans into new, fixed and persisting.
+    issue_key :
+        What makes two issues the same.
+
+Docker Vars:
+    None
+
+Output:
+    {"url", "scan": {"scanned_at", "engine", "errors", "warnings", "notices", "access_score"}, "baseline": {...}, "delta": {"errors", "warnings", "notices", "access_score"}, "new": [{"fingerprint", "code", "type", "selector", "context", "message", "suppressed"}], "fixed": [...], "persisting": [...]}
+    The issues of "fixed" are those of the baseline, the others those of the latest scan. access_score is null if a scan has none, and so is its delta.
+
+Errors:
+    url is required :
+        400 if the query string has no url.
+    no stored scans of {} :
+        404 if the crawls table has no row for the URL, or none by the given engine.
+    only one stored scan of {}, nothing to compare :
+        404 if no baseline is given and the URL was scanned only once.
+    no stored scan of {} at {} :
+        404 if the baseline is not the time of a stored scan of the URL.
+    baseline must be an earlier scan than the latest :
+        400 if the baseline is the latest scan.
+    failed to read scans from google big query: {} :
+        500 if the crawls or issues could not be read.
+*/
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StoredIssue {
+    #[serde(skip)]
+    pub scanned_at: String,
+    pub fingerprint: String,
+    pub code: String,
+    #[serde(rename = "type")]
+    pub issue_type: String,
+    pub selector: String,
+    pub context: String,
+    pub message: String,
+    pub suppressed: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ScanSummary {
+    pub scanned_at: String,
+    pub engine: String,
+    pub errors: i64,
+    pub warnings: i64,
+    pub notices: i64,
+    pub access_score: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Delta {
+    errors: i64,
+    warnings: i64,
+    notices: i64,
+    access_score: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Diff {
+    url: String,
+    scan: ScanSummary,
+    baseline: ScanSummary,
+    delta: Delta,
+    new: Vec<StoredIssue>,
+    fixed: Vec<StoredIssue>,
+    persisting: Vec<StoredIssue>,
+}
+
+// Two issues are the same if they have the same fingerprint
+fn issue_key(issue: &StoredIssue) -> &str {
+    &issue.fingerprint
+}
+
+// Sort the issues of the latest scan and the baseline into new, fixed and persisting
+fn classify(
+    latest: Vec<StoredIssue>,
+    baseline: Vec<StoredIssue>,
+) -> (Vec<StoredIssue>, Vec<StoredIssue>, Vec<StoredIssue>) {
+    let latest_keys: HashSet<_> = latest.iter().map(issue_key).collect();
+    let baseline_keys: HashSet<_> = baseline.iter().map(issue_key).collect();
+
+    // An issue reported twice in one scan is only listed once
+    let mut seen = HashSet::new();
+    let (mut new, mut persisting) = (Vec::new(), Vec::new());
+    for issue in &latest {
+        if !seen.insert(issue_key(issue)) {
+            continue;
+        }
+        if baseline_keys.contains(&issue_key(issue)) {
+            persisting.push(issue.clone());
+        } else {
+            new.push(issue.clone());
+        }
+    }
+    let mut seen = HashSet::new();
+    let fixed = baseline
+        .iter()
+        .filter(|issue| seen.insert(issue_key(issue)) && !latest_keys.contains(&issue_key(issue)))
+        .cloned()
+        .collect();
+    (new, fixed, persisting)
+}
+
+// Compare the latest scan of a page to the previous one or a given baseline
+#[get("/scan/diff?<url>&<baseline>&<engine>")]
+pub(crate) fn catch_scan_diff(
+    url: Option<String>,
+    baseline: Option<String>,
+    engine: Option<String>,
+    caller: Caller,
+) -> Result<String, status::Custom<String>> {
+    // Check the signature of signed requests, a GET has no body
+    caller.verify_body(&[])?;
+    caller.require("scan:read")?;
+
+    let url =
+        url.ok_or_else(|| status::Custom(Status::BadRequest, "url is required".to_owned()))?;
+    let failed = |e| {
+        status::Custom(
+            Status::InternalServerError,
+            format!("failed to read scans from google big query: {}", e),
+        )
+    };
+
+    // Create a new runtime to run async tasks
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    // The scans come newest first, the latest one and the baseline
+    let scans = rt
+        .block_on(read_scan_summaries(
+            "rusty_a11y".to_owned(),
+            &url,
+            baseline.as_deref(),
+            engine.as_deref(),
+        ))
+        .map_err(failed)?;
+    let latest = scans
+        .first()
+        .cloned()
+        .ok_or_else(|| status::Custom(Status::NotFound, format!("no stored scans of {}", url)))?;
+    let base = match &baseline {
+        Some(baseline) => scans
+            .iter()
+            .find(|scan| &scan.scanned_at == baseline)
+            .cloned()
+            .ok_or_else(|| {
+                status::Custom(
+                    Status::NotFound,
+                    format!("no stored scan of {} at {}", url, baseline),
+                )
+            })?,
+        None => scans.get(1).cloned().ok_or_else(|| {
+            status::Custom(
+                Status::NotFound,
+                format!("only one stored scan of {}, nothing to compare", url),
+            )
+        })?,
+    };
+    if base.scanned_at == latest.scanned_at {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "baseline must be an earlier scan than the latest".to_owned(),
+        ));
+    }
+
+    let (latest_issues, baseline_issues) = rt
+        .block_on(read_scan_issues(
+            "rusty_a11y".to_owned(),
+            &url,
+            &latest.engine,
+            &[&latest.scanned_at, &base.scanned_at],
+        ))
+        .map_err(failed)?
+        .into_iter()
+        .partition(|issue| issue.scanned_at == latest.scanned_at);
+    let (new, fixed, persisting) = classify(latest_issues, baseline_issues);
+
+    let delta = Delta {
+        errors: latest.errors - base.errors,
+        warnings: latest.warnings - base.warnings,
+        notices: latest.notices - base.notices,
+        access_score: latest
+            .access_score
+            .zip(base.access_score)
+            .map(|(latest, base)| latest - base),
+    };
+    Ok(serde_json::to_string(&Diff {
+        url,
+        scan: latest,
+        baseline: base,
+        delta,
+        new,
+        fixed,
+        persisting,
+    })
+    .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, StoredIssue};
+
+    fn issue(fingerprint: &str, selector: &str) -> StoredIssue {
+        StoredIssue {
+            scanned_at: String::new(),
+            fingerprint: fingerprint.to_owned(),
+            code: "WCAG2AA.Principle1.Guideline1_1.1_1_1.H37".to_owned(),
+            issue_type: "error".to_owned(),
+            selector: selector.to_owned(),
+            context: String::new(),
+            message: String::new(),
+            suppressed: false,
+        }
+    }
+
+    fn fingerprints(issues: &[StoredIssue]) -> Vec<&str> {
+        issues
+            .iter()
+            .map(|issue| issue.fingerprint.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn classify_sorts_new_fixed_and_persisting() {
+        let latest = vec![issue("a", "#a"), issue("b", "#b")];
+        let baseline = vec![issue("b", "#b"), issue("c", "#c")];
+        let (new, fixed, persisting) = classify(latest, baseline);
+        assert_eq!(fingerprints(&new), ["a"]);
+        assert_eq!(fingerprints(&fixed), ["c"]);
+        assert_eq!(fingerprints(&persisting), ["b"]);
+    }
+
+    #[test]
+    fn classify_matches_on_fingerprint_only() {
+        // The selector changed between scans but the normalized fingerprint did not
+        let (new, fixed, persisting) = classify(
+            vec![issue("a", ".css-9z8y7x")],
+            vec![issue("a", ".css-1q2w3e")],
+        );
+        assert!(new.is_empty());
+        assert!(fixed.is_empty());
+        assert_eq!(persisting[0].selector, ".css-9z8y7x");
+    }
+
+    #[test]
+    fn classify_lists_duplicates_once() {
+        let latest = vec![issue("a", "#a"), issue("a", "#a"), issue("b", "#b")];
+        let baseline = vec![issue("b", "#b"), issue("c", "#c"), issue("c", "#c")];
+        let (new, fixed, persisting) = classify(latest, baseline);
+        assert_eq!(fingerprints(&new), ["a"]);
+        assert_eq!(fingerprints(&fixed), ["c"]);
+        assert_eq!(fingerprints(&persisting), ["b"]);
+    }
+
+    #[test]
+    fn classify_handles_empty_scans() {
+        let (new, fixed, persisting) = classify(Vec::new(), vec![issue("a", "#a")]);
+        assert!(new.is_empty() && persisting.is_empty());
+        assert_eq!(fingerprints(&fixed), ["a"]);
+        let (new, fixed, persisting) = classify(vec![issue("a", "#a")], Vec::new());
+        assert!(fixed.is_empty() && persisting.is_empty());
+        assert_eq!(fingerprints(&new), ["a"]);
+    }
+}
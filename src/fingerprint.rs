@@ -0,0 +1,249 @@
+use regex::{Captures, Regex};
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+/*
+Code Summary:
+This module gives every mapped issue a fingerprint, so one issue can be followed from scan to scan and duplicates can be found. The fingerprint is the hex SHA-256 of the URL, the issue code, the normalized selector and the normalized context of the issue. It is stored in the fingerprint column of the issues table and /scan/diff uses it to match issues.
+
+Normalization keeps what identifies the element and drops what changes between page loads:
+    - class names and ids that look generated (e.g. "css-1q2w3e", "Button_primary__3xYz1", "item-48213") have their generated part replaced by "*", and the names of CSS-in-JS classes ("css-", "sc-", "jsx-", "emotion-", "svelte-") are replaced entirely,
+    - in the context, the values of attributes that hold session data (names containing "session", "token", "nonce" or "csrf", and React ids) are replaced by "*",
+    - in other attribute values, session parameters of URLs (jsessionid, phpsessid, sid, token, ...) and long hex strings or UUIDs are replaced by "*",
+    - whitespace is collapsed.
+
+Variables:
+    CSS_IN_JS :
+        Prefixes of class names generated by CSS-in-JS libraries.
+
+Functions:
+    add_fingerprints :
+        Adds the fingerprint column to every mapped issue row.
+    fingerprint :
+        The fingerprint of an issue.
+    normalize_selector :
+        Replaces the generated parts of the class names and ids of a CSS selector.
+    normalize_context :
+        Replaces the volatile attribute values of an HTML snippet and collapses its whitespace.
+    normalize_name :
+        Replaces the generated parts of one class name or id.
+
+Docker Vars:
+    None
+
+Output:
+    A 64 character hex string, e.g. "3f7a...".
+
+Errors:
+    None
+*/
+
+const CSS_IN_JS: [&str; 5] = ["css", "sc", "jsx", "emotion", "svelte"];
+
+fn regex(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).unwrap())
+}
+
+// Replace the generated part of a class name or id
+fn normalize_name(name: &str) -> String {
+    let segments: Vec<&str> = name.split(['-', '_']).collect();
+    if segments.len() > 1 && CSS_IN_JS.contains(&segments[0]) {
+        return "*".to_owned();
+    }
+    let generated = |segment: &str| {
+        let digits = segment.chars().filter(|c| c.is_ascii_digit()).count();
+        (digits >= 3 && digits == segment.len())
+            || (segment.len() >= 5
+                && digits > 0
+                && segment.chars().any(|c| c.is_ascii_alphabetic()))
+    };
+    // Keep the separators, so "Button_primary__3xYz1" becomes "Button_primary__*"
+    let mut normalized = String::new();
+    let mut segment = String::new();
+    for c in name.chars().chain(std::iter::once('\0')) {
+        if c == '-' || c == '_' || c == '\0' {
+            normalized.push_str(if generated(&segment) { "*" } else { &segment });
+            segment.clear();
+            if c != '\0' {
+                normalized.push(c);
+            }
+        } else {
+            segment.push(c);
+        }
+    }
+    normalized
+}
+
+// Replace the generated class names and ids of a CSS selector
+pub fn normalize_selector(selector: &str) -> String {
+    static NAME: OnceLock<Regex> = OnceLock::new();
+    let selector = regex(&NAME, r"([.#])(-?[A-Za-z_][\w-]*)")
+        .replace_all(selector, |caps: &Captures| {
+            format!("{}{}", &caps[1], normalize_name(&caps[2]))
+        });
+    selector.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Replace the volatile attribute values of an HTML snippet
+pub fn normalize_context(context: &str) -> String {
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    static SESSION_PARAM: OnceLock<Regex> = OnceLock::new();
+    static HEX: OnceLock<Regex> = OnceLock::new();
+    let context = regex(
+        &ATTRIBUTE,
+        r#"([A-Za-z_:][\w:.-]*)(\s*=\s*)("[^"]*"|'[^']*'|[^\s"'>]+)"#,
+    )
+    .replace_all(context, |caps: &Captures| {
+        let name = caps[1].to_lowercase();
+        let quoted = &caps[3];
+        let (quote, value) = match quoted.chars().next() {
+            Some(q @ ('"' | '\'')) => (q.to_string(), &quoted[1..quoted.len() - 1]),
+            _ => (String::new(), quoted),
+        };
+        let value = if ["session", "token", "nonce", "csrf"]
+            .iter()
+            .any(|volatile| name.contains(volatile))
+            || name == "data-reactid"
+        {
+            "*".to_owned()
+        } else if name == "class" || name == "id" || name == "for" {
+            value
+                .split_whitespace()
+                .map(normalize_name)
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            let value = regex(
+                &SESSION_PARAM,
+                r"(?i)([?&;](?:jsessionid|phpsessid|sessionid|session|sid|token|csrf\w*|nonce)=)[^&;#?\s]*",
+            )
+            .replace_all(value, "${1}*");
+            regex(
+                &HEX,
+                r"(?i)\b(?:[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}|[0-9a-f]{16,})\b",
+            )
+            .replace_all(&value, "*")
+            .into_owned()
+        };
+        format!("{}{}{}{}{}", &caps[1], &caps[2], quote, value, quote)
+    });
+    context.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// The fingerprint of an issue on a page
+pub fn fingerprint(url: &str, code: &str, selector: &str, context: &str) -> String {
+    let mut hasher = Sha256::new();
+    for part in [
+        url,
+        code,
+        &normalize_selector(selector),
+        &normalize_context(context),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+// Add the fingerprint column to every mapped issue row
+pub fn add_fingerprints(rows: &mut JsonValue) {
+    match rows {
+        JsonValue::Array(rows) => rows.iter_mut().for_each(add_fingerprints),
+        JsonValue::Object(row) => {
+            let column = |name: &str| row.get(name).and_then(|v| v.as_str()).unwrap_or_default();
+            let fingerprint = fingerprint(
+                column("url"),
+                column("issue_code"),
+                column("selector"),
+                column("context"),
+            );
+            row.insert("fingerprint".to_owned(), JsonValue::String(fingerprint));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint, normalize_context, normalize_name, normalize_selector};
+
+    #[test]
+    fn normalize_name_replaces_generated_parts() {
+        assert_eq!(normalize_name("css-1q2w3e"), "*");
+        assert_eq!(normalize_name("sc-bdVaJa"), "*");
+        assert_eq!(normalize_name("Button_primary__3xYz1"), "Button_primary__*");
+        assert_eq!(normalize_name("item-48213"), "item-*");
+        assert_eq!(normalize_name("nav-main"), "nav-main");
+        assert_eq!(normalize_name("col-12"), "col-12");
+        assert_eq!(normalize_name("h2"), "h2");
+    }
+
+    #[test]
+    fn normalize_selector_keeps_structure() {
+        assert_eq!(
+            normalize_selector("#main > .css-1q2w3e  .Button_primary__3xYz1"),
+            "#main > .* .Button_primary__*"
+        );
+        assert_eq!(
+            normalize_selector("ul > li:nth-child(2) > a"),
+            "ul > li:nth-child(2) > a"
+        );
+    }
+
+    #[test]
+    fn normalize_context_replaces_volatile_values() {
+        assert_eq!(
+            normalize_context(r#"<a href="/cart;jsessionid=0A1B2C3D?x=1">Cart</a>"#),
+            r#"<a href="/cart;jsessionid=*?x=1">Cart</a>"#
+        );
+        assert_eq!(
+            normalize_context("<a href='/login?sid=abc&next=/home'>Log in</a>"),
+            "<a href='/login?sid=*&next=/home'>Log in</a>"
+        );
+        assert_eq!(
+            normalize_context(r#"<input name="csrf_token" value="x9y8z7">"#),
+            r#"<input name="csrf_token" value="x9y8z7">"#
+        );
+        assert_eq!(
+            normalize_context(r#"<input data-csrf-token="x9y8z7" data-reactid=".0.1">"#),
+            r#"<input data-csrf-token="*" data-reactid="*">"#
+        );
+        assert_eq!(
+            normalize_context(r#"<img src="/i/0123456789abcdef0123.png" class="css-1q2w3e hero">"#),
+            r#"<img src="/i/*.png" class="* hero">"#
+        );
+        assert_eq!(
+            normalize_context("<div\n   id=\"item-48213\">\n  Text </div>"),
+            r#"<div id="item-*"> Text </div>"#
+        );
+    }
+
+    #[test]
+    fn fingerprint_ignores_volatile_values() {
+        let url = "https://example.gov/";
+        let first = fingerprint(
+            url,
+            "WCAG2AA.Principle1.Guideline1_1.1_1_1.H37",
+            ".css-1q2w3e > img",
+            r#"<a href="/;jsessionid=AAAA"><img class="Button_primary__3xYz1"></a>"#,
+        );
+        let second = fingerprint(
+            url,
+            "WCAG2AA.Principle1.Guideline1_1.1_1_1.H37",
+            ".css-9z8y7x > img",
+            r#"<a href="/;jsessionid=BBBB"><img class="Button_primary__8aBc2"></a>"#,
+        );
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 64);
+        assert_ne!(
+            first,
+            fingerprint(
+                "https://example.gov/other",
+                "WCAG2AA.Principle1.Guideline1_1.1_1_1.H37",
+                ".css-1q2w3e > img",
+                r#"<a href="/;jsessionid=AAAA"><img class="Button_primary__3xYz1"></a>"#,
+            )
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use crate::auth::Caller;
+use crate::bigquery::store as bq_store;
+use crate::scan::ScanOptions;
+use crate::scanner::{map_pages, AxeReport, Mapped, Pa11yReport, ScanError, Scanner};
+use reqwest::Client;
+use rocket::{http::Status, post, response::status, Data};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::io::Read;
+
+/*
+Code Summary:
+This module stores the results of accessibility engines other than A11yWatch, so pages tested with pa11y or axe-core in a CI pipeline or a browser extension end up in the same tables as the scans of /scan and /crawl. POST /scan/import takes a report in the format of the engine, reads its pages into the common issue model (see scanner.rs) and maps, fingerprints, flags and stores them like a scan. The stored scans can then be compared with /scan/diff and reported on like any other.
+
+    {"format": "axe", "url": "https://example.gov/form", "report": {"url": "...", "timestamp": "...", "violations": [...], "incomplete": [...]}}
+
+Variables:
+    caller :
+        The API key, signed request or bearer token that made the request. It needs the "scan:run" scope.
+    ImportData :
+        The body of the request: the format, the report, an optional url for pages the report has no url for, and the ScanOptions the report was produced with, which are recorded with the rows.
+    MAX_REPORT_BYTES :
+        The largest body accepted, reports of many pages are larger than a scan request.
+
+Functions:
+    catch_scan_import :
+        Rocket endpoint for POST /scan/import. Reads the body and runs the import.
+    run_import :
+        Reads the pages of the report with a Scanner, maps them and stores their rows.
+
+Docker Vars:
+    None
+
+Output:
+    {"format", "pages", "results": [...]}
+    results holds the mapped result of every page, as returned by /crawl.
+
+Errors:
+    failed to read body data: {} / failed to parse body data: {} :
+        400 if the body could not be read or parsed.
+    format must be pa11y or axe, got {} :
+        400 for an unknown format.
+    invalid {} report: {} :
+        400 if the report could not be read, or a page of it has no url and the request none either.
+    the report has no pages :
+        400 if the report holds no page.
+    failed to map json data: {:?} :
+        500 if the pages could not be mapped.
+    failed to store data to google big query: {} :
+        500 if the rows could not be stored.
+*/
+
+const MAX_REPORT_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct ImportData {
+    format: String,
+    report: JsonValue,
+    url: Option<String>,
+    #[serde(flatten)]
+    options: ScanOptions,
+}
+
+#[post("/scan/import", data = "<raw_data>")]
+pub fn catch_scan_import(
+    raw_data: Data,
+    caller: Caller,
+) -> Result<rocket::response::content::Json<String>, status::Custom<String>> {
+    // Read the request body, limited to 10 MB, and check the signature of signed requests
+    let mut buf = Vec::new();
+    raw_data
+        .open()
+        .take(MAX_REPORT_BYTES)
+        .read_to_end(&mut buf)
+        .map_err(|e| {
+            status::Custom(
+                Status::BadRequest,
+                format!("failed to read body data: {}", e),
+            )
+        })?;
+    caller.verify_body(&buf)?;
+
+    // Make sure the caller is allowed to run scans
+    caller.require("scan:run")?;
+
+    let data: ImportData = serde_json::from_slice(&buf).map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            format!("failed to parse body data: {}", e),
+        )
+    })?;
+    data.options.validate()?;
+
+    let output = match data.format.as_str() {
+        "pa11y" => run_import(
+            Pa11yReport {
+                report: data.report,
+                url: data.url,
+            },
+            &data.options,
+        ),
+        "axe" => run_import(
+            AxeReport {
+                report: data.report,
+                url: data.url,
+            },
+            &data.options,
+        ),
+        format => Err(status::Custom(
+            Status::BadRequest,
+            format!("format must be pa11y or axe, got {}", format),
+        )),
+    }?;
+    Ok(rocket::response::content::Json(output.to_string()))
+}
+
+// Read the pages of a report, map them and store their rows
+fn run_import<S: Scanner>(
+    scanner: S,
+    options: &ScanOptions,
+) -> Result<JsonValue, status::Custom<String>> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let pages = rt
+        .block_on(scanner.pages(&Client::new()))
+        .map_err(|e| match e {
+            ScanError::Invalid(e) => status::Custom(
+                Status::BadRequest,
+                format!("invalid {} report: {}", scanner.engine(), e),
+            ),
+            // Reports are read without A11yWatch, these only come from a scan
+            ScanError::Failed(e) => e,
+            ScanError::A11y(e) => status::Custom(Status::InternalServerError, format!("{:?}", e)),
+        })?;
+    if pages.is_empty() {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "the report has no pages".to_owned(),
+        ));
+    }
+
+    let Mapped {
+        issues,
+        crawls,
+        result,
+    } = map_pages(scanner.engine(), &pages, true, options).map_err(|e| {
+        status::Custom(
+            Status::InternalServerError,
+            format!("failed to map json data: {:?}", e),
+        )
+    })?;
+
+    // Store the issues and crawls rows in BigQuery
+    for (table, rows) in [("issues", &issues), ("crawls", &crawls)] {
+        if rows.as_array().map_or(false, Vec::is_empty) {
+            continue;
+        }
+        rt.block_on(bq_store("rusty_a11y".to_owned(), table.to_owned(), rows))
+            .map_err(|e| {
+                status::Custom(
+                    Status::InternalServerError,
+                    format!("failed to store data to google big query: {}", e),
+                )
+            })?;
+    }
+
+    Ok(json!({
+        "format": scanner.engine(),
+        "pages": pages.len(),
+        "results": result,
+    }))
+}
@@ -0,0 +1,288 @@
+use crate::bigquery::{close_incident, read_open_incidents, store as bq_store};
+use crate::up::ProbeResult;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/*
+Code Summary:
+This module turns the results of /up checks into outage incidents. Incidents are kept per target and probe type, so a dns and an http probe of the same host each have their own. The previous state of a target and probe type is read from the incidents table: one with an incident that has no ended_at is down, any other is up. A check that finds an up target down opens a new incident, a check that finds a down target up or degraded again closes its incident with the time it ended and how long it lasted. A degraded target can be reached, it fails an assertion of its response (see up.rs), so it does not open an incident. Checks that do not change the state of a target leave the incidents table alone. An incident opened and ended within one batch of checks is stored with its end, since the UPDATE that closes incidents only sees rows stored by earlier cycles.
+
+Variables:
+    IncidentKey :
+        The target and probe type of an incident.
+
+    Incident :
+        A newly opened incident, stored as one row of the incidents table.
+
+    Closing :
+        The end time and duration of an incident stored by an earlier cycle.
+
+Functions:
+    track :
+        Compares the checks with the open incidents, stores the incidents that were opened and closes the incidents of targets that recovered. Returns the number of opened and closed incidents.
+
+    transitions :
+        Sorts the checks into incidents to store and incidents to close, updating the map of open incidents.
+
+    millis :
+        Parses the probed_at time of a check into milliseconds since the unix epoch.
+
+Docker Vars:
+    None
+
+Output:
+    Rows of the incidents table:
+        {"target", "probe_type", "started_at", "ended_at", "duration_seconds", "status_code", "error_class", "error"}
+    started_at is the probed_at time of the check that found the target down, ended_at that of the check that found it up or degraded again. status_code, error_class and error describe the check that opened the incident.
+
+Errors:
+    failed to read open incidents: {} :
+        Indicates that the open incidents could not be read from Google BigQuery.
+
+    failed to store incidents: {} :
+        Indicates that the opened incidents could not be stored in Google BigQuery.
+
+    failed to close incident of {} ({}): {} :
+        Indicates that the incident of a recovered target could not be updated in Google BigQuery.
+*/
+
+// The target and probe type an incident is kept for
+type IncidentKey = (String, String);
+
+#[derive(Serialize, Debug)]
+struct Incident {
+    target: String,
+    probe_type: String,
+    started_at: String,
+    ended_at: Option<String>,
+    duration_seconds: Option<i64>,
+    status_code: Option<u16>,
+    error_class: Option<String>,
+    error: Option<String>,
+}
+
+// The end of an incident stored by an earlier cycle
+#[derive(Debug, PartialEq)]
+struct Closing {
+    target: String,
+    probe_type: String,
+    ended_at: String,
+    duration_seconds: i64,
+}
+
+// Parse a probed_at time, e.g. "2023-02-01 12:00:00.000 UTC"
+fn millis(probed_at: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(probed_at, "%Y-%m-%d %H:%M:%S%.3f UTC")
+        .ok()
+        .map(|time| Utc.from_utc_datetime(&time).timestamp_millis())
+}
+
+// Compare the checks with the open incidents, returning the incidents to store and those to close
+fn transitions(
+    open: &mut HashMap<IncidentKey, i64>,
+    results: &[ProbeResult],
+) -> (Vec<Incident>, Vec<Closing>) {
+    let mut opened: Vec<Incident> = Vec::new();
+    let mut closings = Vec::new();
+    // Incidents opened by this batch are not stored yet, they are ended in place
+    let mut pending: HashMap<IncidentKey, usize> = HashMap::new();
+    for result in results {
+        let key = (result.target.clone(), result.probe_type.clone());
+        let is_open = open.contains_key(&key) || pending.contains_key(&key);
+        match (result.state == "down", is_open) {
+            // up or degraded -> down
+            (true, false) => {
+                if let Some(started_ms) = millis(&result.probed_at) {
+                    open.insert(key.clone(), started_ms);
+                }
+                pending.insert(key, opened.len());
+                opened.push(Incident {
+                    target: result.target.clone(),
+                    probe_type: result.probe_type.clone(),
+                    started_at: result.probed_at.clone(),
+                    ended_at: None,
+                    duration_seconds: None,
+                    status_code: result.status_code,
+                    error_class: result.error_class.clone(),
+                    error: result.error.clone(),
+                });
+            }
+            // down -> up or degraded
+            (false, true) => {
+                let duration = open
+                    .remove(&key)
+                    .zip(millis(&result.probed_at))
+                    .map(|(started_ms, ended_ms)| (ended_ms - started_ms).max(0) / 1000)
+                    .unwrap_or(0);
+                match pending.remove(&key) {
+                    Some(index) => {
+                        opened[index].ended_at = Some(result.probed_at.clone());
+                        opened[index].duration_seconds = Some(duration);
+                    }
+                    None => closings.push(Closing {
+                        target: result.target.clone(),
+                        probe_type: result.probe_type.clone(),
+                        ended_at: result.probed_at.clone(),
+                        duration_seconds: duration,
+                    }),
+                }
+            }
+            // No change
+            _ => {}
+        }
+    }
+    (opened, closings)
+}
+
+// Open incidents for targets that went down and close those of targets that can be reached again
+pub async fn track(results: &[ProbeResult]) -> Result<(usize, usize), String> {
+    let mut open = read_open_incidents("rusty_a11y".to_owned())
+        .await
+        .map_err(|e| format!("failed to read open incidents: {}", e))?;
+    let (opened, closings) = transitions(&mut open, results);
+
+    // Incidents that opened and ended in the same batch are stored already closed
+    let count = opened.len();
+    let closed = opened.iter().filter(|i| i.ended_at.is_some()).count() + closings.len();
+    if count > 0 {
+        bq_store(
+            "rusty_a11y".to_owned(),
+            "incidents".to_owned(),
+            &serde_json::to_value(opened).unwrap(),
+        )
+        .await
+        .map_err(|e| format!("failed to store incidents: {}", e))?;
+    }
+    for closing in closings {
+        close_incident(
+            "rusty_a11y".to_owned(),
+            &closing.target,
+            &closing.probe_type,
+            &closing.ended_at,
+            closing.duration_seconds,
+        )
+        .await
+        .map_err(|e| {
+            format!(
+                "failed to close incident of {} ({}): {}",
+                closing.target, closing.probe_type, e
+            )
+        })?;
+    }
+    Ok((count, closed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{millis, transitions, Closing};
+    use crate::up::ProbeResult;
+    use std::collections::HashMap;
+
+    fn result(probe_type: &str, state: &str, probed_at: &str) -> ProbeResult {
+        ProbeResult {
+            target: "example.gov".to_owned(),
+            probe_type: probe_type.to_owned(),
+            status: state == "up",
+            state: state.to_owned(),
+            status_code: None,
+            response_time_ms: 0,
+            redirect_chain: "[]".to_owned(),
+            final_url: None,
+            response_size: None,
+            dns_answers: None,
+            cert_issuer: None,
+            cert_sans: None,
+            cert_not_before: None,
+            cert_expires_at: None,
+            cert_chain_valid: None,
+            cert_days_left: None,
+            cert_expiring: None,
+            error_class: None,
+            error: None,
+            probed_at: probed_at.to_owned(),
+            attempts: 1,
+            attempt_outcomes: "[]".to_owned(),
+        }
+    }
+
+    fn key(probe_type: &str) -> (String, String) {
+        ("example.gov".to_owned(), probe_type.to_owned())
+    }
+
+    #[test]
+    fn probe_types_of_one_host_have_their_own_incidents() {
+        let mut open = HashMap::new();
+
+        // dns fails while tls and http pass: one incident, for dns
+        let cycle = [
+            result("dns", "down", "2024-01-01 00:00:00.000 UTC"),
+            result("tls", "up", "2024-01-01 00:00:00.000 UTC"),
+            result("http", "up", "2024-01-01 00:00:00.000 UTC"),
+        ];
+        let (opened, closings) = transitions(&mut open, &cycle);
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0].probe_type, "dns");
+        assert!(opened[0].ended_at.is_none());
+        assert!(closings.is_empty());
+        assert_eq!(open.keys().collect::<Vec<_>>(), [&key("dns")]);
+
+        // The same states in the next cycle change nothing
+        let (opened, closings) = transitions(&mut open, &cycle);
+        assert!(opened.is_empty());
+        assert!(closings.is_empty());
+
+        // Once dns recovers only its incident is closed
+        let (opened, closings) = transitions(
+            &mut open,
+            &[
+                result("dns", "up", "2024-01-01 00:10:00.000 UTC"),
+                result("http", "degraded", "2024-01-01 00:10:00.000 UTC"),
+            ],
+        );
+        assert!(opened.is_empty());
+        assert_eq!(
+            closings,
+            [Closing {
+                target: "example.gov".to_owned(),
+                probe_type: "dns".to_owned(),
+                ended_at: "2024-01-01 00:10:00.000 UTC".to_owned(),
+                duration_seconds: 600,
+            }]
+        );
+        assert!(open.is_empty());
+    }
+
+    #[test]
+    fn incident_opened_and_ended_in_one_batch_is_stored_closed() {
+        let mut open = HashMap::new();
+        let (opened, closings) = transitions(
+            &mut open,
+            &[
+                result("http", "down", "2024-01-01 00:00:00.000 UTC"),
+                result("http", "up", "2024-01-01 00:01:30.000 UTC"),
+            ],
+        );
+        assert_eq!(opened.len(), 1);
+        assert_eq!(
+            opened[0].ended_at.as_deref(),
+            Some("2024-01-01 00:01:30.000 UTC")
+        );
+        assert_eq!(opened[0].duration_seconds, Some(90));
+        assert!(closings.is_empty());
+        assert!(open.is_empty());
+    }
+
+    #[test]
+    fn stored_incident_is_closed_with_its_duration() {
+        let mut open =
+            HashMap::from([(key("tls"), millis("2024-01-01 00:00:00.000 UTC").unwrap())]);
+        let (opened, closings) = transitions(
+            &mut open,
+            &[result("tls", "up", "2024-01-01 01:00:00.000 UTC")],
+        );
+        assert!(opened.is_empty());
+        assert_eq!(closings.len(), 1);
+        assert_eq!(closings[0].duration_seconds, 3600);
+    }
+}
@@ -0,0 +1,334 @@
+use crate::auth::Caller;
+use crate::get_env_or;
+use chrono::{DateTime, Utc};
+use rocket::{get, http::Status, response::status, State};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/*
+Code Summary:
+This module runs /scan, /scan/batch, /scan/sitemap, /crawl and /up requests as background jobs, so long crawls and cycles do not hold the HTTP connection open past proxy timeouts. A request with "async": true in its body is checked and authorized as usual, then answered at once with 202 Accepted and a job id while the work runs on its own thread. At most JOBS_MAX_RUNNING jobs run at a time, a request for another is answered with 503 Service Unavailable so each job's thread and runtime cannot pile up. The job records its progress, the errors of single targets and, once it is done, the mapped result the request would have returned. GET /jobs/<id> reports one job and GET /jobs lists the recent ones. Jobs are kept in memory, the oldest finished ones are dropped once there are more than JOBS_KEEP.
+
+Variables:
+    JobStatus :
+        queued, running, succeeded or failed.
+    TargetError :
+        The error of one target of a job, e.g. a URL of a batch that could not be scanned or an up target that was found down.
+    JobState :
+        The status, times, progress, target errors, result and error of a job.
+    Job :
+        A job with its id, kind, target, owner and JobState.
+    JobRegistry :
+        All kept jobs, oldest first, and the number of jobs that may run at a time. It is handed to Rocket as managed state.
+
+Functions:
+    wants_async :
+        Whether a request body asks to run as a job.
+    JobRegistry::spawn :
+        Adds a job and runs the given work on a new thread, unless JOBS_MAX_RUNNING jobs have not finished yet. A panic fails the job instead of leaving it running.
+    Job::set_total, Job::advance, Job::target_error :
+        Called by the work to report how many targets there are, that one is done, and why one failed.
+    accepted :
+        The body of the 202 Accepted response.
+    catch_job :
+        Rocket endpoint for GET /jobs/<id>.
+    catch_jobs :
+        Rocket endpoint for GET /jobs, newest first, without the results.
+
+Docker Vars:
+    JOBS_KEEP :
+        Number of jobs kept in memory, default 100. Running jobs are never dropped.
+    JOBS_MAX_RUNNING :
+        Number of jobs that may be queued or running at a time, default 8.
+
+Output:
+    The request that starts a job returns 202 Accepted with {"id", "status", "status_url"}.
+    GET /jobs/<id> returns {"id", "kind", "target", "status", "created_at", "started_at", "finished_at", "done", "total", "errors": [{"target", "error"}], "result", "error"}. result holds the response of the request once it succeeded, error why it failed.
+    GET /jobs returns a list of the same objects without result.
+
+Errors:
+    {} jobs are already running, retry later :
+        503 if a request asks for a job while JOBS_MAX_RUNNING jobs have not finished.
+    job {} not found :
+        404 if there is no job with the id, it was dropped, or it belongs to another caller.
+    A caller sees the jobs it started. Callers with the "jobs:read" scope (or admin) see every job.
+*/
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct TargetError {
+    target: String,
+    error: String,
+}
+
+#[derive(Clone)]
+struct JobState {
+    status: JobStatus,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    done: usize,
+    total: usize,
+    errors: Vec<TargetError>,
+    result: Option<JsonValue>,
+    error: Option<String>,
+}
+
+pub struct Job {
+    id: String,
+    kind: &'static str,
+    target: Option<String>,
+    owner: String,
+    created_at: DateTime<Utc>,
+    state: Mutex<JobState>,
+}
+
+#[derive(Serialize)]
+struct JobReport {
+    id: String,
+    kind: &'static str,
+    target: Option<String>,
+    status: JobStatus,
+    created_at: String,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    done: usize,
+    total: usize,
+    errors: Vec<TargetError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<JsonValue>,
+    error: Option<String>,
+}
+
+pub struct JobRegistry {
+    jobs: Mutex<Vec<Arc<Job>>>,
+    keep: usize,
+    max_running: usize,
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+// Whether the body asks for the request to run as a background job
+pub fn wants_async(data: &serde_json::Map<String, JsonValue>) -> bool {
+    data.get("async").and_then(|v| v.as_bool()).unwrap_or(false)
+}
+
+// The body of the 202 Accepted response to a request that started a job
+pub fn accepted(job: &Job) -> String {
+    serde_json::json!({
+        "id": job.id,
+        "status": job.state.lock().unwrap().status,
+        "status_url": format!("/jobs/{}", job.id),
+    })
+    .to_string()
+}
+
+impl Job {
+    // Set the number of targets of the job
+    pub fn set_total(&self, total: usize) {
+        self.state.lock().unwrap().total = total;
+    }
+
+    // Count one target as done
+    pub fn advance(&self) {
+        self.state.lock().unwrap().done += 1;
+    }
+
+    // Record why a target failed, the job goes on with the others
+    pub fn target_error(&self, target: &str, error: &str) {
+        self.state.lock().unwrap().errors.push(TargetError {
+            target: target.to_owned(),
+            error: error.to_owned(),
+        });
+    }
+
+    fn report(&self, with_result: bool) -> JobReport {
+        let state = self.state.lock().unwrap().clone();
+        JobReport {
+            id: self.id.clone(),
+            kind: self.kind,
+            target: self.target.clone(),
+            status: state.status,
+            created_at: format_time(self.created_at),
+            started_at: state.started_at.map(format_time),
+            finished_at: state.finished_at.map(format_time),
+            done: state.done,
+            total: state.total,
+            errors: state.errors,
+            result: if with_result { state.result } else { None },
+            error: state.error,
+        }
+    }
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        JobRegistry {
+            jobs: Mutex::new(Vec::new()),
+            keep: get_env_or("JOBS_KEEP", 100),
+            max_running: get_env_or("JOBS_MAX_RUNNING", 8),
+        }
+    }
+
+    // Add a job for the caller and run the work on its own thread, if there is room for another
+    pub fn spawn<F>(
+        &self,
+        caller: &Caller,
+        kind: &'static str,
+        target: Option<String>,
+        total: usize,
+        work: F,
+    ) -> Result<Arc<Job>, status::Custom<String>>
+    where
+        F: FnOnce(&Job) -> Result<JsonValue, status::Custom<String>> + Send + 'static,
+    {
+        let mut id = [0; 16];
+        openssl::rand::rand_bytes(&mut id).unwrap();
+        let job = Arc::new(Job {
+            id: hex::encode(id),
+            kind,
+            target,
+            owner: caller.name(),
+            created_at: Utc::now(),
+            state: Mutex::new(JobState {
+                status: JobStatus::Queued,
+                started_at: None,
+                finished_at: None,
+                done: 0,
+                total,
+                errors: Vec::new(),
+                result: None,
+                error: None,
+            }),
+        });
+
+        // Keep the newest jobs, but never drop one that has not finished
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            let running = jobs
+                .iter()
+                .filter(|job| {
+                    matches!(
+                        job.state.lock().unwrap().status,
+                        JobStatus::Queued | JobStatus::Running
+                    )
+                })
+                .count();
+            if running >= self.max_running {
+                return Err(status::Custom(
+                    Status::ServiceUnavailable,
+                    format!("{} jobs are already running, retry later", running),
+                ));
+            }
+            jobs.push(job.clone());
+            while jobs.len() > self.keep {
+                match jobs.iter().position(|job| {
+                    matches!(
+                        job.state.lock().unwrap().status,
+                        JobStatus::Succeeded | JobStatus::Failed
+                    )
+                }) {
+                    Some(oldest) => jobs.remove(oldest),
+                    None => break,
+                };
+            }
+        }
+
+        let runner = job.clone();
+        thread::spawn(move || {
+            {
+                let mut state = runner.state.lock().unwrap();
+                state.status = JobStatus::Running;
+                state.started_at = Some(Utc::now());
+            }
+            println!("jobs: starting {} job {}", runner.kind, runner.id);
+
+            // Run on a separate thread so a panic fails the job
+            let worker = runner.clone();
+            let outcome = thread::spawn(move || work(&worker))
+                .join()
+                .unwrap_or_else(|_| {
+                    Err(status::Custom(
+                        Status::InternalServerError,
+                        "the job panicked".to_owned(),
+                    ))
+                });
+
+            let mut state = runner.state.lock().unwrap();
+            state.finished_at = Some(Utc::now());
+            match outcome {
+                Ok(result) => {
+                    state.status = JobStatus::Succeeded;
+                    state.done = state.total;
+                    state.result = Some(result);
+                }
+                Err(e) => {
+                    state.status = JobStatus::Failed;
+                    state.error = Some(e.1);
+                }
+            }
+            println!("jobs: {} job {} {:?}", runner.kind, runner.id, state.status);
+        });
+        Ok(job)
+    }
+
+    // The kept jobs the caller may see, oldest first
+    fn visible(&self, caller: &Caller) -> Vec<Arc<Job>> {
+        let all = caller.require("jobs:read").is_ok();
+        let owner = caller.name();
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|job| all || job.owner == owner)
+            .cloned()
+            .collect()
+    }
+}
+
+// Report the status, progress and result of a job
+#[get("/jobs/<id>")]
+pub(crate) fn catch_job(
+    id: String,
+    registry: State<JobRegistry>,
+    caller: Caller,
+) -> Result<String, status::Custom<String>> {
+    // Check the signature of signed requests, a GET has no body
+    caller.verify_body(&[])?;
+
+    let job = registry
+        .visible(&caller)
+        .into_iter()
+        .find(|job| job.id == id)
+        .ok_or_else(|| status::Custom(Status::NotFound, format!("job {} not found", id)))?;
+    Ok(serde_json::to_string(&job.report(true)).unwrap())
+}
+
+// List the recent jobs, newest first
+#[get("/jobs")]
+pub(crate) fn catch_jobs(
+    registry: State<JobRegistry>,
+    caller: Caller,
+) -> Result<String, status::Custom<String>> {
+    // Check the signature of signed requests, a GET has no body
+    caller.verify_body(&[])?;
+
+    let jobs: Vec<JobReport> = registry
+        .visible(&caller)
+        .iter()
+        .rev()
+        .map(|job| job.report(false))
+        .collect();
+    Ok(serde_json::to_string(&jobs).unwrap())
+}
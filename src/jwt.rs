@@ -0,0 +1,227 @@
+use crate::auth::require_scope;
+use crate::get_env;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::status;
+use rocket::{Outcome, State};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+/*
+Code Summary:
+This module lets callers authenticate with "Authorization: Bearer <jwt>" tokens issued by an identity provider, next to the x-auth keys handled in auth.rs. Tokens are validated against the public keys of a JWKS file on disk: the signature must match the key named by the token's "kid", and the issuer, audience and expiry must be valid. The scopes of the token are read from a configurable claim and checked against the same endpoint scopes as API keys (see auth.rs).
+
+Variables:
+    Jwks:
+        The key set, expected issuer and audience, and scope claim name. It is loaded once at launch and handed to Rocket as managed state.
+
+    Claims:
+        The claims read from a validated token. Every claim other than sub and iss is kept in extra so the scope claim can be looked up by name.
+
+    TokenError:
+        An enum type that specifies the possible errors that can occur while checking a bearer token.
+
+    JwtBearer:
+        A struct that represents a validated token: its subject, issuer and scopes.
+
+Functions:
+    Jwks::load:
+        Reads the JWKS file named by JWKS_FILE. If that variable is not set, bearer tokens are not accepted.
+
+    from_request:
+        This method is called by Rocket when it receives an incoming request. It validates the bearer token in the Authorization header and returns an Outcome that is either a JwtBearer or a TokenError.
+
+    require:
+        Returns a 403 Forbidden error if the token does not hold the given scope (or admin).
+
+Docker Vars:
+    JWKS_FILE:
+        Path to the JSON Web Key Set of the identity provider.
+
+    JWT_ISSUER:
+        Expected "iss" claim. Required when JWKS_FILE is set.
+
+    JWT_AUDIENCE:
+        Expected "aud" claim. Required when JWKS_FILE is set.
+
+    JWT_SCOPE_CLAIM:
+        Name of the claim holding the scopes, either a space separated string or a list. Defaults to "scope".
+
+Output:
+    None
+
+Errors:
+    BadCount:
+        Returned when there are multiple Authorization headers.
+
+    Malformed:
+        Returned when the token cannot be decoded or uses an algorithm its key does not allow.
+
+    UnknownKey:
+        Returned when the token's "kid" is not in the JWKS file.
+
+    BadSignature:
+        Returned when the signature does not match the key.
+
+    Expired:
+        Returned when the token is expired or not valid yet.
+
+    WrongIssuer:
+        Returned when the "iss" claim does not match JWT_ISSUER.
+
+    WrongAudience:
+        Returned when the "aud" claim does not match JWT_AUDIENCE.
+
+    NotSet:
+        Returned when JWKS_FILE is not set.
+*/
+
+pub struct Jwks {
+    keys: JwkSet,
+    issuer: String,
+    audience: String,
+    scope_claim: String,
+}
+
+impl Jwks {
+    // Load the key set from JWKS_FILE, bearer tokens are disabled if it is not set
+    pub fn load() -> Result<Self, String> {
+        let path = match get_env("JWKS_FILE") {
+            Ok(path) => path,
+            Err(_) => {
+                return Ok(Jwks {
+                    keys: JwkSet { keys: Vec::new() },
+                    issuer: String::new(),
+                    audience: String::new(),
+                    scope_claim: String::new(),
+                })
+            }
+        };
+        let file = std::fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read jwks file {}: {}", path, e))?;
+        let keys: JwkSet = serde_json::from_str(&file)
+            .map_err(|e| format!("failed to parse jwks file {}: {}", path, e))?;
+        Ok(Jwks {
+            keys,
+            issuer: get_env("JWT_ISSUER")?,
+            audience: get_env("JWT_AUDIENCE")?,
+            scope_claim: get_env("JWT_SCOPE_CLAIM").unwrap_or_else(|_| "scope".to_owned()),
+        })
+    }
+
+    // Validate a token and read its subject, issuer and scopes
+    fn validate(&self, token: &str) -> Result<JwtBearer, TokenError> {
+        let header = decode_header(token).map_err(|_| TokenError::Malformed)?;
+        let jwk = header
+            .kid
+            .as_ref()
+            .and_then(|kid| self.keys.find(kid))
+            .ok_or(TokenError::UnknownKey)?;
+
+        // Only accept the algorithm the key is meant for
+        if jwk.common.algorithm.map_or(false, |alg| alg != header.alg) {
+            return Err(TokenError::Malformed);
+        }
+        let key = DecodingKey::from_jwk(jwk).map_err(|_| TokenError::Malformed)?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+
+        let claims = decode::<Claims>(token, &key, &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::InvalidSignature => TokenError::BadSignature,
+                ErrorKind::ExpiredSignature | ErrorKind::ImmatureSignature => TokenError::Expired,
+                ErrorKind::InvalidIssuer => TokenError::WrongIssuer,
+                ErrorKind::InvalidAudience => TokenError::WrongAudience,
+                _ => TokenError::Malformed,
+            })?
+            .claims;
+
+        // Scopes are either a space separated string or a list of strings
+        let scopes = match claims.extra.get(&self.scope_claim) {
+            Some(JsonValue::String(s)) => s.split_whitespace().map(|s| s.to_owned()).collect(),
+            Some(JsonValue::Array(list)) => list
+                .iter()
+                .filter_map(|s| s.as_str().map(|s| s.to_owned()))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(JwtBearer {
+            subject: claims.sub,
+            issuer: claims.iss,
+            scopes,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    iss: String,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, JsonValue>,
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    BadCount,      // too many Authorization headers
+    Malformed,     // token cannot be decoded
+    UnknownKey,    // kid not in the key set
+    BadSignature,  // signature does not match
+    Expired,       // token expired or not valid yet
+    WrongIssuer,   // iss does not match
+    WrongAudience, // aud does not match
+    NotSet,        // no key set configured
+}
+
+pub struct JwtBearer {
+    subject: String,
+    issuer: String,
+    scopes: Vec<String>,
+}
+
+impl JwtBearer {
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    // Check that the token holds the scope needed for the requested action
+    pub fn require(&self, scope: &str) -> Result<(), status::Custom<String>> {
+        require_scope(&self.subject, &self.scopes, scope)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for JwtBearer {
+    type Error = TokenError;
+
+    // Validate the bearer token in the Authorization header
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let jwks = match request.guard::<State<Jwks>>() {
+            Outcome::Success(jwks) if !jwks.keys.keys.is_empty() => jwks,
+            _ => return Outcome::Failure((Status::Unauthorized, TokenError::NotSet)), // Bearer tokens not configured
+        };
+        let headers: Vec<_> = request.headers().get("Authorization").collect();
+        if headers.len() != 1 {
+            return Outcome::Failure((Status::BadRequest, TokenError::BadCount));
+            // Multiple Authorization headers
+        }
+        let token = headers[0].trim_start_matches("Bearer ").trim();
+        match jwks.validate(token) {
+            Ok(bearer) => {
+                // Report which token made the request
+                println!(
+                    "auth: {} {} by token '{}' ({})",
+                    request.method(),
+                    request.uri(),
+                    bearer.subject,
+                    bearer.issuer
+                );
+                Outcome::Success(bearer)
+            }
+            Err(e) => Outcome::Failure((Status::Unauthorized, e)),
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::get_env_or;
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/*
+Code Summary:
+This module limits how often each caller may use the service. Every caller has a token bucket that refills at a steady rate per minute and holds up to a burst of requests, and an optional daily quota per endpoint that resets at midnight UTC. The Caller guard in auth.rs checks the limiter after authenticating a request; when a limit is hit the request is rejected with 429 Too Many Requests. A signed request is only checked by the guard, it is counted once verify_body has checked its signature, so requests with a bad signature do not use up the quota of the key they name. Quotas count requests per route (e.g. "/jobs/<id>"), and the counts of past days are dropped at midnight UTC. The RateLimitHeaders fairing then copies the caller's remaining requests, remaining quota and, for rejected requests, the Retry-After delay into the response headers.
+
+Variables:
+    Limits:
+        The rate limit and daily quotas of one caller. API keys may set them in the key registry under "limits", bearer tokens and keys without limits use the defaults.
+
+    Bucket:
+        The token bucket of one caller: the tokens left and when it was last refilled.
+
+    RateLimiter:
+        The token buckets of every caller and today's request counts per caller and route. It is handed to Rocket as managed state, and cloned into signed requests that are counted later.
+
+    Usage:
+        The result of one check.
+
+    RequestUsage:
+        The Usage of one request, kept in the request's local cache for the RateLimitHeaders fairing. A signed request shares it with its charge, so verify_body can replace the checked usage with the counted one.
+
+Functions:
+    Limits::default_limits:
+        Returns the limits from RATE_LIMIT_PER_MINUTE and RATE_LIMIT_BURST, without daily quotas.
+
+    RateLimiter::check:
+        Takes one token from the caller's bucket and counts one request against the route's daily quota. Returns the Usage, with the reason and retry delay set if the request must be rejected. With charge set to false the limits are only checked and nothing is counted, the Usage is the one the request would leave if it were counted.
+
+    on_response:
+        Adds the X-RateLimit-Remaining, X-Quota-Limit, X-Quota-Remaining and Retry-After headers to the response.
+
+Docker Vars:
+    RATE_LIMIT_PER_MINUTE:
+        Default number of requests a caller's bucket refills per minute. Defaults to 60.
+
+    RATE_LIMIT_BURST:
+        Default number of requests a caller's bucket holds. Defaults to 10.
+
+Output:
+    Response headers:
+        X-RateLimit-Remaining - whole requests left in the caller's bucket
+        X-Quota-Limit         - the caller's daily quota for the endpoint, if any
+        X-Quota-Remaining     - requests left today for the endpoint, if a quota is set
+        Retry-After           - seconds until the request may be retried, on 429 responses
+
+Errors:
+    RateLimited:
+        The caller's bucket is empty.
+
+    QuotaExceeded:
+        The caller's daily quota for the endpoint is used up.
+*/
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Limits {
+    pub per_minute: f64,
+    pub burst: f64,
+    // Daily request limit per route, e.g. {"/scan": 100} or {"/jobs/<id>": 1000}
+    #[serde(default)]
+    pub daily: HashMap<String, u32>,
+}
+
+impl Limits {
+    pub fn default_limits() -> Self {
+        Limits {
+            per_minute: get_env_or("RATE_LIMIT_PER_MINUTE", 60.0),
+            burst: get_env_or("RATE_LIMIT_BURST", 10.0),
+            daily: HashMap::new(),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum LimitError {
+    RateLimited,   // bucket is empty
+    QuotaExceeded, // daily quota for the endpoint is used up
+}
+
+#[derive(Clone, Debug)]
+pub struct Usage {
+    pub rate_remaining: u64,
+    pub quota: Option<(u32, u32)>, // daily limit and requests left today
+    pub rejected: Option<(LimitError, u64)>, // reason and seconds until the request may be retried
+}
+
+// The usage of one request, set by the Caller guard and by verify_body for signed requests
+#[derive(Clone, Default)]
+pub struct RequestUsage(Arc<Mutex<Option<Usage>>>);
+
+impl RequestUsage {
+    pub fn set(&self, usage: Usage) {
+        *self.0.lock().unwrap() = Some(usage);
+    }
+
+    fn get(&self) -> Option<Usage> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+// The day the counts are for and the requests per caller and route on that day
+type DailyCounts = (NaiveDate, HashMap<(String, String), u32>);
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    daily: Arc<Mutex<DailyCounts>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            daily: Arc::new(Mutex::new((Utc::now().date_naive(), HashMap::new()))),
+        }
+    }
+
+    // Count one request of the caller against its bucket and the route's daily quota,
+    // or only check the limits without counting it
+    pub fn check(&self, caller: &str, limits: &Limits, route: &str, charge: bool) -> Usage {
+        // The counts of a past day are dropped
+        let today = Utc::now().date_naive();
+        let mut daily = self.daily.lock().unwrap();
+        if daily.0 != today {
+            *daily = (today, HashMap::new());
+        }
+        let count = daily
+            .1
+            .entry((caller.to_owned(), route.to_owned()))
+            .or_insert(0);
+
+        // Refill the bucket for the time passed since the last request
+        let now = Instant::now();
+        let rate = limits.per_minute / 60.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(caller.to_owned()).or_insert(Bucket {
+            tokens: limits.burst,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate)
+            .min(limits.burst);
+        bucket.updated = now;
+
+        let quota = limits.daily.get(route).copied();
+        let rejected = match quota {
+            // Quota used up, retry after midnight UTC
+            Some(limit) if *count >= limit => {
+                let midnight = (today + ChronoDuration::days(1))
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap();
+                let seconds = (midnight - Utc::now().naive_utc()).num_seconds().max(1);
+                Some((LimitError::QuotaExceeded, seconds as u64))
+            }
+            // Bucket empty, retry once a whole token has refilled
+            _ if bucket.tokens < 1.0 => {
+                let seconds = if rate > 0.0 {
+                    ((1.0 - bucket.tokens) / rate).ceil() as u64
+                } else {
+                    60
+                };
+                Some((LimitError::RateLimited, seconds))
+            }
+            _ => None,
+        };
+
+        // A checked request that passes reports its usage as if it had been counted
+        let used = u32::from(rejected.is_none());
+        if charge && used == 1 {
+            bucket.tokens -= 1.0;
+            *count += 1;
+        }
+        let (tokens, count) = if charge {
+            (bucket.tokens, *count)
+        } else {
+            (bucket.tokens - f64::from(used), *count + used)
+        };
+        Usage {
+            rate_remaining: tokens.floor() as u64,
+            quota: quota.map(|limit| (limit, limit.saturating_sub(count))),
+            rejected,
+        }
+    }
+}
+
+pub struct RateLimitHeaders;
+
+impl Fairing for RateLimitHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit headers",
+            kind: Kind::Response,
+        }
+    }
+
+    // Copy the usage the Caller guard stored for this request into the response headers
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Some(usage) = request.local_cache(RequestUsage::default).get() {
+            response.set_header(Header::new(
+                "X-RateLimit-Remaining",
+                usage.rate_remaining.to_string(),
+            ));
+            if let Some((limit, remaining)) = usage.quota {
+                response.set_header(Header::new("X-Quota-Limit", limit.to_string()));
+                response.set_header(Header::new("X-Quota-Remaining", remaining.to_string()));
+            }
+            if let Some((_, retry_after)) = usage.rejected {
+                response.set_header(Header::new("Retry-After", retry_after.to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LimitError, Limits, RateLimiter};
+    use chrono::{Duration as ChronoDuration, Utc};
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    fn limits(per_minute: f64, burst: f64, daily: &[(&str, u32)]) -> Limits {
+        Limits {
+            per_minute,
+            burst,
+            daily: daily
+                .iter()
+                .map(|(route, limit)| (route.to_string(), *limit))
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    // Move the last refill of a caller's bucket into the past
+    fn age_bucket(limiter: &RateLimiter, caller: &str, seconds: u64) {
+        let mut buckets = limiter.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(caller).unwrap();
+        bucket.updated = Instant::now() - Duration::from_secs(seconds);
+    }
+
+    #[test]
+    fn burst_is_used_up_then_rejected() {
+        let limiter = RateLimiter::new();
+        let limits = limits(60.0, 3.0, &[]);
+        for remaining in [2, 1, 0] {
+            let usage = limiter.check("key:a", &limits, "/scan", true);
+            assert!(usage.rejected.is_none());
+            assert_eq!(usage.rate_remaining, remaining);
+        }
+        let usage = limiter.check("key:a", &limits, "/scan", true);
+        assert!(matches!(usage.rejected, Some((LimitError::RateLimited, 1))));
+
+        // Other callers have their own bucket
+        assert!(limiter
+            .check("key:b", &limits, "/scan", true)
+            .rejected
+            .is_none());
+    }
+
+    #[test]
+    fn bucket_refills_up_to_burst() {
+        let limiter = RateLimiter::new();
+        let limits = limits(60.0, 3.0, &[]);
+        for _ in 0..3 {
+            limiter.check("key:a", &limits, "/scan", true);
+        }
+
+        // One token a second
+        age_bucket(&limiter, "key:a", 2);
+        let usage = limiter.check("key:a", &limits, "/scan", true);
+        assert!(usage.rejected.is_none());
+        assert_eq!(usage.rate_remaining, 1);
+
+        // Never more than the burst
+        age_bucket(&limiter, "key:a", 3600);
+        let usage = limiter.check("key:a", &limits, "/scan", true);
+        assert_eq!(usage.rate_remaining, 2);
+    }
+
+    #[test]
+    fn daily_quota_is_per_route_and_resets() {
+        let limiter = RateLimiter::new();
+        let limits = limits(60.0, 10.0, &[("/scan", 2)]);
+        assert_eq!(
+            limiter.check("key:a", &limits, "/scan", true).quota,
+            Some((2, 1))
+        );
+        assert_eq!(
+            limiter.check("key:a", &limits, "/scan", true).quota,
+            Some((2, 0))
+        );
+        let usage = limiter.check("key:a", &limits, "/scan", true);
+        match usage.rejected {
+            Some((LimitError::QuotaExceeded, seconds)) => assert!((1..=86400).contains(&seconds)),
+            rejected => panic!("expected the quota to be exceeded, got {:?}", rejected),
+        }
+        // A rejected request does not use a token
+        assert_eq!(usage.rate_remaining, 8);
+
+        // Routes without a quota and other callers are not affected
+        let other = limiter.check("key:a", &limits, "/crawl", true);
+        assert!(other.rejected.is_none());
+        assert_eq!(other.quota, None);
+        assert!(limiter
+            .check("key:b", &limits, "/scan", true)
+            .rejected
+            .is_none());
+
+        // The counts of a past day are dropped
+        limiter.daily.lock().unwrap().0 = Utc::now().date_naive() - ChronoDuration::days(1);
+        let usage = limiter.check("key:a", &limits, "/scan", true);
+        assert!(usage.rejected.is_none());
+        assert_eq!(usage.quota, Some((2, 1)));
+    }
+
+    #[test]
+    fn check_without_charge_counts_nothing() {
+        let limiter = RateLimiter::new();
+        let limits = limits(60.0, 2.0, &[("/jobs/<id>", 1)]);
+
+        // Reports the usage the request would leave, without counting it
+        for _ in 0..3 {
+            let usage = limiter.check("key:a", &limits, "/jobs/<id>", false);
+            assert!(usage.rejected.is_none());
+            assert_eq!(usage.rate_remaining, 1);
+            assert_eq!(usage.quota, Some((1, 0)));
+        }
+
+        // Once counted, the next check is rejected and still counts nothing
+        limiter.check("key:a", &limits, "/jobs/<id>", true);
+        for _ in 0..2 {
+            let usage = limiter.check("key:a", &limits, "/jobs/<id>", false);
+            assert!(matches!(
+                usage.rejected,
+                Some((LimitError::QuotaExceeded, _))
+            ));
+            assert_eq!(usage.rate_remaining, 1);
+            assert_eq!(usage.quota, Some((1, 0)));
+        }
+    }
+}
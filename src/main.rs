@@ -3,31 +3,55 @@ extern crate rocket;
 use rocket::config::{Config, Environment};
 use rocket::routes;
 
+mod a11ywatch;
+mod audit;
 mod auth;
 pub mod bigquery;
+mod cache;
 mod crawl;
+mod diff;
+mod fingerprint;
+mod import;
+mod incident;
+mod jobs;
+mod jwt;
+mod limit;
 mod map_json;
+mod page_auth;
+mod report;
 mod scan;
+mod scanner;
+mod schedule;
+mod sitemap;
 mod status;
+mod suppress;
 mod up;
 mod util;
 
 /*
 Code Summary:
-The purpose of this module is to handle incoming HTTP requests and forward them to the appropriate sub-module. An API key is used for basic protection of the system, and requests without the correct key are rejected with "Not Authorized".
+The purpose of this module is to handle incoming HTTP requests and forward them to the appropriate sub-module. API keys are used for basic protection of the system, and requests without a valid key are rejected with "Not Authorized".
 
 API Key:
-The API key is set as the "api_key" Docker environment variable. The user must provide the correct key value in the "x-auth" field of the header in order to access the system.
+The API keys are read from the JSON file named by the "API_KEYS_FILE" Docker environment variable (see auth.rs for the format and scopes). The user must provide a registered, unexpired key in the "x-auth" field of the header, and the key must hold the scope of the endpoint. If "API_KEYS_FILE" is not set, the legacy "API_KEY" variable is accepted as a single admin key. Instead of "x-auth", a caller can send an HMAC-SHA256 signature of the request made with its key's secret in the "x-key-id", "x-timestamp" and "x-signature" headers, or an "Authorization: Bearer" token from the identity provider configured in jwt.rs.
 
 Function:
 - `get_env`: retrieves the value of a given environment variable or returns an error if the variable is missing.
-- `main`: configures and launches the Rocket application, mounting the routes for each sub-module.
+- `get_env_or`: retrieves and parses a given environment variable, or returns the default if it is missing or cannot be parsed.
+- `main`: configures and launches the Rocket application, mounting the routes for each sub-module. It also starts the jobs of the scheduler (see schedule.rs).
 */
 
 pub fn get_env(name: &'static str) -> Result<String, String> {
     std::env::var(name).map_err(|_| format!("missing environment variable: {}", name))
 }
 
+pub fn get_env_or<T: std::str::FromStr>(name: &'static str, default: T) -> T {
+    get_env(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
 fn main() {
     // Configure Rocket with the specified environment settings
     let config = Config::build(Environment::Development)
@@ -36,11 +60,37 @@ fn main() {
         .finalize()
         .unwrap();
 
+    // Load the API key registry and the key set for bearer tokens
+    let key_registry = auth::KeyRegistry::load().unwrap();
+    let jwks = jwt::Jwks::load().unwrap();
+
+    // Load the suppression rules applied to every scan and the named page credentials
+    suppress::load().unwrap();
+    page_auth::load().unwrap();
+
+    // Load the scheduled jobs and start running them
+    let scheduler = schedule::Scheduler::load().unwrap();
+    scheduler.start();
+
     // Mount the routes for each module
     rocket::custom(config)
+        .manage(key_registry)
+        .manage(jwks)
+        .manage(limit::RateLimiter::new())
+        .manage(scheduler)
+        .manage(jobs::JobRegistry::new())
+        .attach(limit::RateLimitHeaders)
         .mount("/", routes![up::catch_up])
+        .mount("/", routes![report::catch_report])
+        .mount("/", routes![audit::catch_audit])
         .mount("/", routes![scan::catch_scan])
+        .mount("/", routes![scan::catch_scan_batch])
+        .mount("/", routes![sitemap::catch_scan_sitemap])
+        .mount("/", routes![import::catch_scan_import])
+        .mount("/", routes![diff::catch_scan_diff])
         .mount("/", routes![crawl::catch_crawl])
+        .mount("/", routes![schedule::catch_schedule])
+        .mount("/", routes![jobs::catch_job, jobs::catch_jobs])
         .mount("/", routes![status::catch_ready])
         .mount("/", routes![status::catch_health])
         .launch();
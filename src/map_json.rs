@@ -17,7 +17,7 @@ Functions:
     map(&self, input: &Value):
         This function maps the provided JSON input to the target structure based on the stored mapping information in keys.
 
-    map_internal(&self, keys: &HashMap<Vec<String>, String>, input: &Value):
+    map_internal(keys: &HashMap<Vec<String>, String>, input: &Value):
         This function is a recursive helper function that maps a JSON input to the target structure based on the provided keys hashmap.
 
 Docker Vars:
@@ -90,13 +90,10 @@ impl JsonMapper {
 
     /// Map the `input` JSON value to the target schema.
     pub fn map(&self, input: &Value) -> Result<Value, JsonMapperError> {
-        self.map_internal(&self.keys, input)
-            .map(|x| x.ok_or(JsonMapperError::Empty))
-            .flatten()
+        Self::map_internal(&self.keys, input).and_then(|x| x.ok_or(JsonMapperError::Empty))
     }
 
     fn map_internal(
-        &self,
         keys: &HashMap<Vec<String>, String>,
         input: &Value,
     ) -> Result<Option<Value>, JsonMapperError> {
@@ -120,7 +117,7 @@ impl JsonMapper {
                 let mut new_items = Vec::new();
 
                 // Map each item of the input array recursively.
-                for item_opt_res in items.iter().map(|i| self.map_internal(keys, i)) {
+                for item_opt_res in items.iter().map(|i| Self::map_internal(keys, i)) {
                     let item_opt = item_opt_res?;
                     // unwrap: `keys` is unchanged for this recursive call, so if the call
                     // would return `Ok(None)`, we would have returned `Ok(None)` aswell
@@ -148,8 +145,12 @@ impl JsonMapper {
                 let mut merge_array = None;
 
                 // For each unique top-level key in the `keys` hash map.
-                for k in keys.keys().filter_map(|x| x.get(0)).unique() {
-                    let mapped_name = keys.iter().find(|(k2, _v)| k2.get(0) == Some(k)).unwrap().1;
+                for k in keys.keys().filter_map(|x| x.first()).unique() {
+                    let mapped_name = keys
+                        .iter()
+                        .find(|(k2, _v)| k2.first() == Some(k))
+                        .unwrap()
+                        .1;
                     if let Some(mapping_value) = obj.get(k) {
                         let new_keys = keys
                             .iter()
@@ -170,7 +171,7 @@ impl JsonMapper {
                                 continue;
                             }
                         }
-                        match self.map_internal(&new_keys, mapping_value)? {
+                        match Self::map_internal(&new_keys, mapping_value)? {
                             Some(Value::Object(output)) => {
                                 for (k, v) in output.iter() {
                                     map.insert(k.to_owned(), v.clone());
@@ -0,0 +1,370 @@
+use crate::get_env;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::Url;
+use rocket::{http::Status, response::status};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::OnceLock;
+
+/*
+Code Summary:
+This module lets /scan, /scan/batch and /crawl reach pages behind a login. A request can carry an "auth" with cookies, extra request headers and basic auth credentials, which are forwarded to A11yWatch as the pageHeaders of the scan or crawl. The auth is either the name of an entry in the JSON file named by PAGE_AUTH_FILE, or given inline:
+
+    "auth": "portal"
+    "auth": {"cookies": {"session": "..."}, "headers": {"X-Api-Key": "..."}, "basic_auth": {"username": "...", "password": "..."}}
+
+The file maps names to the same objects, each with the hosts its credentials may be sent to, and should be mounted as a secret:
+
+    {"portal": {"hosts": ["https://portal.example.gov"], "cookies": {"session": "..."}, "basic_auth": {"username": "scanner", "password": "..."}}}
+
+hosts is required and lists origins: a scheme, a host and an optional port. A named entry is only used for a scan or crawl whose URL has the scheme, host and port of one of them, so a caller cannot have A11yWatch send the credentials of an entry to a site of its choosing. A crawl with a named entry cannot follow subdomains or other top level domains for the same reason. Inline credentials belong to the caller that sends them and are not restricted.
+
+Crawl targets (the auth column of crawl_targets) and scheduled scans can only reference entries by name, so no secret is ever kept in BigQuery or the schedule file. The credentials are never stored, logged or returned: ScanData and CrawlData skip them when serialized, their Debug output is redacted, and scans with credentials are not cached so a result seen with one login is never served to another caller.
+
+Variables:
+    BasicAuth :
+        A username and password, sent as an "Authorization: Basic" header.
+    Credentials :
+        The cookies, headers and basic auth of a page.
+    PageAuth :
+        A reference to an entry of PAGE_AUTH_FILE or inline Credentials.
+    NamedCredentials :
+        An entry of PAGE_AUTH_FILE: the hosts it may be sent to and its Credentials.
+    CREDENTIALS :
+        The entries read at launch, by name.
+
+Functions:
+    load :
+        Reads and validates PAGE_AUTH_FILE. Without it only inline credentials can be used.
+    PageAuth::resolve :
+        Looks up a named entry for the URL of a scan or crawl, or returns the inline credentials.
+    PageAuth::validate :
+        Checks that a named entry exists and may be sent to the URL, and that the headers are valid, before anything is sent to A11yWatch.
+    PageAuth::apply :
+        Adds the pageHeaders of the credentials for the URL to the request body for A11yWatch.
+    NamedCredentials::check :
+        Checks the hosts and headers of an entry of the file.
+    NamedCredentials::allows :
+        Whether the URL has the scheme, host and port of one of the hosts.
+    Credentials::page_headers :
+        The Cookie, Authorization and extra headers as a list of {"key", "value"}.
+
+Docker Vars:
+    PAGE_AUTH_FILE :
+        Path to the JSON object of named page credentials.
+
+Output:
+    None, the credentials only change the request sent to A11yWatch.
+
+Errors:
+    failed to read page auth file {}: {} / failed to parse page auth file {}: {} :
+        The file could not be read or is not an object of credentials. The service does not start.
+    invalid page credentials '{}': {} :
+        An entry of the file has no hosts, a host that is not an http or https origin, or an invalid header. The service does not start.
+    unknown page credentials '{}' :
+        400 if a request references a name that is not in PAGE_AUTH_FILE.
+    page credentials '{}' may not be sent to {} :
+        400 if the URL of the scan or crawl is not on the hosts of the named entry.
+    invalid header name {} / invalid value for header {} :
+        400 if a header name is not a valid HTTP header name or a value holds a line break.
+*/
+
+#[derive(Deserialize, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Credentials {
+    #[serde(default)]
+    pub cookies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum PageAuth {
+    Named(String),
+    Inline(Credentials),
+}
+
+// An entry of PAGE_AUTH_FILE, the origins its credentials may be sent to and the credentials
+#[derive(Deserialize, Clone)]
+struct NamedCredentials {
+    hosts: Vec<String>,
+    #[serde(flatten)]
+    credentials: Credentials,
+}
+
+static CREDENTIALS: OnceLock<HashMap<String, NamedCredentials>> = OnceLock::new();
+
+// Only the names of cookies and headers are shown, never their values
+impl fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BasicAuth([redacted])")
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("cookies", &self.cookies.keys().collect::<Vec<_>>())
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("basic_auth", &self.basic_auth)
+            .finish()
+    }
+}
+
+impl fmt::Debug for PageAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PageAuth::Named(name) => f.debug_tuple("Named").field(name).finish(),
+            PageAuth::Inline(credentials) => f.debug_tuple("Inline").field(credentials).finish(),
+        }
+    }
+}
+
+impl Credentials {
+    // The headers A11yWatch sends with every page request
+    fn page_headers(&self) -> Vec<(String, String)> {
+        let mut headers: Vec<(String, String)> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        if !self.cookies.is_empty() {
+            let cookies: Vec<String> = self
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            headers.push(("Cookie".to_owned(), cookies.join("; ")));
+        }
+        if let Some(basic) = &self.basic_auth {
+            headers.push((
+                "Authorization".to_owned(),
+                format!(
+                    "Basic {}",
+                    openssl::base64::encode_block(
+                        format!("{}:{}", basic.username, basic.password).as_bytes()
+                    )
+                ),
+            ));
+        }
+        headers
+    }
+
+    // Check that every header can be sent, the error names the header but not its value
+    fn check(&self) -> Result<(), String> {
+        for (name, value) in self.page_headers() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name {}", name))?;
+            HeaderValue::from_str(&value)
+                .map_err(|_| format!("invalid value for header {}", name))?;
+        }
+        Ok(())
+    }
+}
+
+impl NamedCredentials {
+    // Every host must be an http or https origin, without a path
+    fn check(&self) -> Result<(), String> {
+        if self.hosts.is_empty() {
+            return Err("hosts must list the sites the credentials may be sent to".to_owned());
+        }
+        for host in &self.hosts {
+            let origin = Url::parse(host)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+                .filter(|url| url.host_str().is_some() && url.path() == "/")
+                .filter(|url| url.query().is_none() && url.fragment().is_none());
+            if origin.is_none() {
+                return Err(format!(
+                    "host {} must be an origin such as https://portal.example.gov",
+                    host
+                ));
+            }
+        }
+        self.credentials.check()
+    }
+
+    // Whether the URL has the scheme, host and port of one of the hosts
+    fn allows(&self, url: &str) -> bool {
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        self.hosts
+            .iter()
+            .any(|host| Url::parse(host).map_or(false, |host| host.origin() == url.origin()))
+    }
+}
+
+// Load the named credentials from PAGE_AUTH_FILE, there are none if it is not set
+pub fn load() -> Result<(), String> {
+    let path = match get_env("PAGE_AUTH_FILE") {
+        Ok(path) => path,
+        Err(_) => {
+            CREDENTIALS.get_or_init(HashMap::new);
+            return Ok(());
+        }
+    };
+    let file = std::fs::read_to_string(&path)
+        .map_err(|e| format!("failed to read page auth file {}: {}", path, e))?;
+    let credentials: HashMap<String, NamedCredentials> = serde_json::from_str(&file)
+        .map_err(|e| format!("failed to parse page auth file {}: {}", path, e))?;
+    for (name, credentials) in &credentials {
+        credentials
+            .check()
+            .map_err(|e| format!("invalid page credentials '{}': {}", name, e))?;
+    }
+    println!(
+        "page_auth: loaded {} credential(s) from {}",
+        credentials.len(),
+        path
+    );
+    CREDENTIALS.get_or_init(|| credentials);
+    Ok(())
+}
+
+impl PageAuth {
+    // The credentials to send with the pages of a URL, a named entry must allow the URL
+    pub fn resolve(&self, url: &str) -> Result<Credentials, String> {
+        resolve_from(self, CREDENTIALS.get(), url)
+    }
+
+    // Reject unknown names, names that may not be sent to the URL and invalid headers with 400 Bad Request
+    pub fn validate(&self, url: &str) -> Result<(), status::Custom<String>> {
+        self.resolve(url)
+            .and_then(|credentials| credentials.check())
+            .map_err(|e| status::Custom(Status::BadRequest, e))
+    }
+
+    // Add the headers of the credentials to the request body for A11yWatch
+    pub fn apply(&self, url: &str, body: &mut JsonValue) -> Result<(), status::Custom<String>> {
+        let credentials = self
+            .resolve(url)
+            .map_err(|e| status::Custom(Status::BadRequest, e))?;
+        if let Some(body) = body.as_object_mut() {
+            let headers: Vec<JsonValue> = credentials
+                .page_headers()
+                .into_iter()
+                .map(|(key, value)| json!({"key": key, "value": value}))
+                .collect();
+            body.insert("pageHeaders".to_owned(), JsonValue::Array(headers));
+        }
+        Ok(())
+    }
+}
+
+// Look up the credentials of a page auth among the named entries
+fn resolve_from(
+    auth: &PageAuth,
+    named: Option<&HashMap<String, NamedCredentials>>,
+    url: &str,
+) -> Result<Credentials, String> {
+    match auth {
+        PageAuth::Named(name) => {
+            let entry = named
+                .and_then(|named| named.get(name))
+                .ok_or_else(|| format!("unknown page credentials '{}'", name))?;
+            if !entry.allows(url) {
+                return Err(format!(
+                    "page credentials '{}' may not be sent to {}",
+                    name, url
+                ));
+            }
+            Ok(entry.credentials.clone())
+        }
+        PageAuth::Inline(credentials) => Ok(credentials.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_from, NamedCredentials, PageAuth};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn entries() -> HashMap<String, NamedCredentials> {
+        serde_json::from_value(json!({
+            "portal": {
+                "hosts": ["https://portal.example.gov", "https://staging.example.gov:8443"],
+                "cookies": {"session": "abc"}
+            }
+        }))
+        .unwrap()
+    }
+
+    fn resolve(auth: &PageAuth, url: &str) -> Result<usize, String> {
+        resolve_from(auth, Some(&entries()), url).map(|c| c.page_headers().len())
+    }
+
+    #[test]
+    fn named_credentials_are_only_sent_to_their_hosts() {
+        let portal = PageAuth::Named("portal".to_owned());
+        assert_eq!(
+            resolve(&portal, "https://portal.example.gov/account"),
+            Ok(1)
+        );
+        assert_eq!(resolve(&portal, "https://portal.example.gov:443/"), Ok(1));
+        assert_eq!(resolve(&portal, "https://staging.example.gov:8443/"), Ok(1));
+
+        for url in [
+            "https://attacker.example/",
+            "http://portal.example.gov/",
+            "https://portal.example.gov:8443/",
+            "https://staging.example.gov/",
+            "https://portal.example.gov.attacker.example/",
+            "not a url",
+        ] {
+            assert_eq!(
+                resolve(&portal, url),
+                Err(format!(
+                    "page credentials 'portal' may not be sent to {}",
+                    url
+                ))
+            );
+        }
+        assert_eq!(
+            resolve(
+                &PageAuth::Named("other".to_owned()),
+                "https://portal.example.gov/"
+            ),
+            Err("unknown page credentials 'other'".to_owned())
+        );
+    }
+
+    #[test]
+    fn inline_credentials_are_not_restricted() {
+        let inline: PageAuth =
+            serde_json::from_value(json!({"headers": {"X-Api-Key": "k"}})).unwrap();
+        assert_eq!(resolve(&inline, "https://anywhere.example/"), Ok(1));
+    }
+
+    #[test]
+    fn entries_need_origins_as_hosts() {
+        let missing = serde_json::from_value::<HashMap<String, NamedCredentials>>(
+            json!({"portal": {"cookies": {"session": "abc"}}}),
+        );
+        assert!(missing.is_err());
+
+        let check = |hosts: serde_json::Value| {
+            serde_json::from_value::<NamedCredentials>(json!({"hosts": hosts}))
+                .unwrap()
+                .check()
+        };
+        assert!(check(json!(["https://portal.example.gov"])).is_ok());
+        assert!(check(json!(["http://localhost:8080/"])).is_ok());
+        assert!(check(json!([])).is_err());
+        assert!(check(json!(["portal.example.gov"])).is_err());
+        assert!(check(json!(["https://portal.example.gov/app"])).is_err());
+        assert!(check(json!(["ftp://portal.example.gov"])).is_err());
+    }
+}
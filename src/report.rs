@@ -0,0 +1,183 @@
+use crate::auth::Caller;
+use crate::bigquery::{read_up_report, UpReportRow};
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, TimeZone, Utc};
+use rocket::get;
+use rocket::{http::Status, response::status};
+use serde::Serialize;
+
+/*
+Code Summary:
+This module answers availability questions about the up targets from the history that up.rs and incident.rs store in BigQuery, so they no longer need hand-written SQL. For every target checked in the requested window it reports the uptime percentage (the share of checks that did not find the target down), the number of degraded checks and their share, the number of incidents, the total downtime and the mean time to recovery. A degraded target could be reached but failed an assertion (see up.rs), so it counts toward the uptime and is reported separately.
+
+The window is either the last "24h", "7d", "30d" (any number of hours or days) before now, or a custom range given by start and end.
+
+Variables:
+    caller :
+        The API key, signed request or bearer token that made the request. It needs the "up:read" scope.
+    TargetReport :
+        The figures of one target, built from its row of the ups and incidents tables.
+    Report :
+        The window of the report and the TargetReport of every target.
+
+Functions:
+    catch_report :
+        Rocket endpoint for GET /up/report. Reads the window and optional target from the query string and returns the Report as JSON.
+    TargetReport::from_row :
+        Computes the uptime and degraded percentages of a target from the counts read by read_up_report.
+    parse_window :
+        Turns a window like "24h" or "7d" into a duration, None if it cannot be parsed or does not fit in one.
+    parse_time :
+        Parses a start or end time, given as an RFC 3339 timestamp or a date.
+
+Docker Vars:
+    None
+
+Output:
+    {"start", "end", "targets": [{"target", "checks", "uptime_percent", "degraded_checks", "degraded_percent", "incidents", "downtime_seconds", "mttr_seconds"}]}
+    uptime_percent and degraded_percent are null if the target has incidents but was not checked in the window. incidents counts every incident that overlaps the window, and downtime_seconds is the part of them inside the window, counting open incidents up to the end of the window. mttr_seconds is the mean duration of those incidents that have ended, null if none have.
+
+Errors:
+    window must be a number of hours or days, e.g. 24h or 7d :
+        Indicates that the window could not be parsed, or reaches further back than dates can be represented.
+
+    start and end must be RFC 3339 timestamps or dates :
+        Indicates that the start or end could not be parsed.
+
+    start must be before end :
+        Indicates that the custom range is empty.
+
+    failed to read report from google big query: {} :
+        Indicates that the report query failed.
+*/
+
+#[derive(Serialize, Debug)]
+pub struct TargetReport {
+    pub target: String,
+    pub checks: i64,
+    pub uptime_percent: Option<f64>,
+    pub degraded_checks: i64,
+    pub degraded_percent: Option<f64>,
+    pub incidents: i64,
+    pub downtime_seconds: i64,
+    pub mttr_seconds: Option<f64>,
+}
+
+impl TargetReport {
+    // The uptime is the share of checks that found the target up or degraded
+    fn from_row(row: UpReportRow) -> Self {
+        let percent = |count: i64| {
+            if row.checks > 0 {
+                Some(count as f64 * 100.0 / row.checks as f64)
+            } else {
+                None
+            }
+        };
+        TargetReport {
+            uptime_percent: percent(row.up_checks + row.degraded_checks),
+            degraded_percent: percent(row.degraded_checks),
+            degraded_checks: row.degraded_checks,
+            target: row.target,
+            checks: row.checks,
+            incidents: row.incidents,
+            downtime_seconds: row.downtime_seconds,
+            mttr_seconds: row.mttr_seconds,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report {
+    start: String,
+    end: String,
+    targets: Vec<TargetReport>,
+}
+
+// Parse a window such as "24h" or "30d"
+fn parse_window(window: &str) -> Option<ChronoDuration> {
+    let amount = |amount: &str| amount.parse::<i64>().ok().filter(|amount| *amount > 0);
+    if let Some(hours) = window.strip_suffix('h') {
+        amount(hours).and_then(ChronoDuration::try_hours)
+    } else if let Some(days) = window.strip_suffix('d') {
+        amount(days).and_then(ChronoDuration::try_days)
+    } else {
+        None
+    }
+}
+
+// Parse a timestamp such as "2023-02-01T12:00:00Z" or a date such as "2023-02-01"
+pub(crate) fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(time, "%Y-%m-%d").ok()?;
+    Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?))
+}
+
+// Report uptime, incidents and recovery time per target over a window
+#[get("/up/report?<window>&<start>&<end>&<target>")]
+pub(crate) fn catch_report(
+    window: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    target: Option<String>,
+    caller: Caller,
+) -> Result<String, status::Custom<String>> {
+    // Check the signature of signed requests, a GET has no body
+    caller.verify_body(&[])?;
+    caller.require("up:read")?;
+
+    // A custom range takes precedence over the window, which defaults to 24h
+    let bad_time = || {
+        status::Custom(
+            Status::BadRequest,
+            "start and end must be RFC 3339 timestamps or dates".to_owned(),
+        )
+    };
+    let end = match end {
+        Some(end) => parse_time(&end).ok_or_else(bad_time)?,
+        None => Utc::now(),
+    };
+    let start = match start {
+        Some(start) => parse_time(&start).ok_or_else(bad_time)?,
+        None => parse_window(window.as_deref().unwrap_or("24h"))
+            .and_then(|window| end.checked_sub_signed(window))
+            .ok_or_else(|| {
+                status::Custom(
+                    Status::BadRequest,
+                    "window must be a number of hours or days, e.g. 24h or 7d".to_owned(),
+                )
+            })?,
+    };
+    if start >= end {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "start must be before end".to_owned(),
+        ));
+    }
+
+    let start = start.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+    let end = end.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+
+    // Create a new runtime to run async tasks
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let rows = rt
+        .block_on(read_up_report(
+            "rusty_a11y".to_owned(),
+            &start,
+            &end,
+            target.as_deref(),
+        ))
+        .map_err(|e| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("failed to read report from google big query: {}", e),
+            )
+        })?;
+
+    Ok(serde_json::to_string(&Report {
+        start,
+        end,
+        targets: rows.into_iter().map(TargetReport::from_row).collect(),
+    })
+    .unwrap())
+}
@@ -1,44 +1,101 @@
 use reqwest::Client;
-use rocket::{http::Status, post, response::status, Data};
+use rocket::{http::Status, post, response::status, Data, State};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value as JsonValue;
-use std::io::{BufReader, Read};
-use std::time::Duration;
+use std::io::Read;
 
-use crate::auth::ApiKey;
+use crate::a11ywatch::A11yError;
+use crate::auth::Caller;
 use crate::bigquery::store as bq_store;
-use crate::get_env;
-use crate::map_json::JsonMapper;
-use crate::util::check_for_error;
+use crate::cache;
+use crate::get_env_or;
+use crate::jobs::{self, Job, JobRegistry};
+use crate::page_auth::PageAuth;
+use crate::scanner::{map_pages, A11yWatch, Mapped, ScanError, Scanner};
+use futures::stream::{self, StreamExt};
 
 /*
 
 Code Summary:
-    This code is a Rust Rocket web service endpoint for scanning a web page for accessibility issues. It sends a request to an accessibility testing service with a JSON payload and receives a JSON response, then applies some JSON mapping logic and stores the results in Google BigQuery.
+    This code is a Rust Rocket web service endpoint for scanning a web page for accessibility issues. It sends a request to an accessibility testing service with a JSON payload and receives a JSON response, then applies some JSON mapping logic and stores the results in Google BigQuery. /scan/batch scans many pages in one request with bounded concurrency, one client and one runtime, and stores the rows of all of them in one insert per table.
 
 Variables:
     ScanData:
-        A struct for holding the JSON data received from the client's request. It includes two fields, url and page_insights.
+        A struct for holding the JSON data received from the client's request. It includes url, page_insights, force, the ScanOptions and the optional page credentials in auth (see page_auth.rs), which are forwarded to A11yWatch but never serialized. force skips the scan cache.
+
+    ScanOptions:
+        The optional WCAG standard (WCAG2A, WCAG2AA or WCAG2AAA), runners (htmlcs and/or axe) and ignored rule codes of a scan or crawl. They are forwarded to A11yWatch and recorded with every stored issues and crawls row, so results of different settings can be told apart. Unset options are null and mean the A11yWatch default.
 
     client:
-        A reqwest::Client used to send the request to the accessibility testing service.
+        A reqwest::Client used to send the request to the accessibility testing service through the A11yWatch scanner (see scanner.rs), which also logs in to A11yWatch when needed.
 
     json_data:
         A JSON object holding the payload to send with the request.
 
-    mapper_bq_issues, mapper_bq, mapper:
-        JsonMapper objects that hold mappings used to apply the JSON data to three different BigQuery tables.
+    pages:
+        The scanned page in the common issue model of scanner.rs, which scanner::map_pages maps to the issues and crawls rows and the result returned to the client.
+
+    BatchData:
+        The body of /scan/batch: a list of ScanData entries under "scans" and an optional "concurrency".
+
+    BatchResult:
+        The outcome of one batch entry: its url, success, and the mapped result or the error.
+
+    ScanRows:
+        The mapped rows of a scan for the issues and crawls tables, the result returned to the client and whether it came from the cache.
 
 Functions:
     catch_scan:
-        A Rocket endpoint function that handles a POST request to the "/scan" path. It reads the JSON payload from the request and sends it to the accessibility testing service using client. Then it applies the JSON mappings to the result, stores the results in BigQuery, and returns the JSON data back to the client.
+        A Rocket endpoint function that handles a POST request to the "/scan" path. It checks that the caller holds the "scan:run" scope, reads the JSON payload, verifies the signature of signed requests and passes it to run_scan, then returns the JSON data back to the client. With "async": true in the body, the scan runs as a background job and the endpoint answers 202 Accepted with the job id (see jobs.rs).
+
+    accepted:
+        The 202 Accepted response of a request that started a job.
+
+    ScanOptions::validate:
+        Rejects unknown standards and runners with 400 Bad Request before anything is sent to A11yWatch.
+
+    ScanOptions::apply:
+        Adds the options that are set to the request body for A11yWatch.
+
+    ScanOptions::annotate:
+        Adds the standard, runners and ignore columns to every mapped row before it is stored. runners and ignore are stored as comma separated lists.
+
+    catch_scan_batch:
+        A Rocket endpoint function that handles a POST request to the "/scan/batch" path. It needs the "scan:run" scope, runs every entry with scan_page, SCAN_CONCURRENCY at a time, and returns a BatchResult per entry in the order they were given. A failed entry does not stop the others, and the rows of the successful ones are stored together. It can also run as a background job with "async": true.
+
+    run_batch:
+        Runs the scans of a batch and stores their rows. Entries with a fresh cached result are not scanned again. As a job it counts every finished scan and records the failed URLs as target errors. /scan/sitemap runs the pages of a sitemap through it (see sitemap.rs).
+
+    rows_of:
+        Turns mapped data, an object or a list, into a list of rows.
+
+    scan_page:
+        Sends one ScanData to A11yWatch and maps the page of the response into ScanRows without storing them. Every issues row gets its fingerprint (see fingerprint.rs) and is flagged if a suppression rule matches it (see suppress.rs), and every row records the engine "a11ywatch".
+
+    run_scan:
+        Serves a fresh result from the scan cache (see cache.rs) unless the scan is forced. Otherwise it sends the ScanData to the accessibility testing service using client. Then it applies the JSON mappings to the result, stores the results in BigQuery, caches the mapped JSON data and returns it. It is also called by the scheduler (see schedule.rs).
+
+Docker Vars:
+    SCAN_CONCURRENCY:
+        Scans of a batch sent to A11yWatch at the same time, default 4. Can be overridden per request with "concurrency".
+
+    SCAN_BATCH_MAX:
+        Maximum number of scans in a batch, default 100.
 
 Output
         The function returns a JSON payload containing the results of the scan.
 
+        /scan/batch returns a list of {"url", "success", "result"} or {"url", "success", "error"}.
+
+        With SCAN_CACHE_TTL set, every result also has "cached" and "scanned_at".
+
+        A request with "async": true returns 202 Accepted with {"id", "status", "status_url"} instead, and the output becomes the result of the job.
+
 Error Messages
-        The function may return a variety of status codes and error messages depending on the stage of the process where an error occurs. For example, if the input data is not properly formatted, the function returns a status code 400 Bad Request with a message indicating the parsing error. If the request to the accessibility testing service fails, the function returns a status code 500 Internal Server Error with a message indicating the reason for the failure. If mapping the JSON data to the BigQuery tables fails, the function returns a status code 500 Internal Server Error with a message indicating the reason for the failure.
+        The function may return a variety of status codes and error messages depending on the stage of the process where an error occurs. For example, if the input data is not properly formatted, or the standard or a runner is not supported, the function returns a status code 400 Bad Request with a message indicating the parsing error. If logging in to or sending the request to the accessibility testing service fails, the function returns a status code 500 Internal Server Error with a message indicating the reason for the failure. If mapping the JSON data to the BigQuery tables fails, the function returns a status code 500 Internal Server Error with a message indicating the reason for the failure.
+
+        In a batch, these errors are returned per entry instead. The batch itself fails with 400 Bad Request if it is empty, holds more than SCAN_BATCH_MAX scans or the concurrency is 0, and with 500 Internal Server Error if the combined rows cannot be stored.
 
 
 
@@ -46,141 +103,466 @@ Error Messages
 
 // Struct for holding the json body data
 #[derive(Serialize, Deserialize, Debug)]
-struct ScanData {
-    url: String,
-    page_insights: bool,
+pub struct ScanData {
+    pub url: String,
+    pub page_insights: bool,
+    #[serde(default)]
+    pub force: bool,
+    #[serde(flatten)]
+    pub options: ScanOptions,
+    #[serde(default, skip_serializing)]
+    pub auth: Option<PageAuth>,
+}
+
+impl ScanData {
+    // Reject unsupported options and unusable credentials before anything is sent to A11yWatch
+    pub fn validate(&self) -> Result<(), rocket::response::status::Custom<std::string::String>> {
+        self.options.validate()?;
+        if let Some(auth) = &self.auth {
+            auth.validate(&self.url)?;
+        }
+        Ok(())
+    }
+}
+
+// The WCAG standards and runners A11yWatch supports
+const STANDARDS: [&str; 3] = ["WCAG2A", "WCAG2AA", "WCAG2AAA"];
+const RUNNERS: [&str; 2] = ["htmlcs", "axe"];
+
+// Optional settings of a scan or crawl, shared with crawl.rs
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ScanOptions {
+    #[serde(default)]
+    pub standard: Option<String>,
+    #[serde(default)]
+    pub runners: Option<Vec<String>>,
+    #[serde(default)]
+    pub ignore: Option<Vec<String>>,
+}
+
+impl ScanOptions {
+    // Check the standard and runners against those A11yWatch supports
+    pub fn validate(&self) -> Result<(), rocket::response::status::Custom<std::string::String>> {
+        if let Some(standard) = &self.standard {
+            if !STANDARDS.contains(&standard.as_str()) {
+                return Err(status::Custom(
+                    Status::BadRequest,
+                    format!(
+                        "standard must be one of {}, got {}",
+                        STANDARDS.join(", "),
+                        standard
+                    ),
+                ));
+            }
+        }
+        if let Some(runners) = &self.runners {
+            if let Some(runner) = runners.iter().find(|r| !RUNNERS.contains(&r.as_str())) {
+                return Err(status::Custom(
+                    Status::BadRequest,
+                    format!("runners must be {}, got {}", RUNNERS.join(" or "), runner),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Add the options that are set to the request body for A11yWatch
+    pub fn apply(&self, body: &mut JsonValue) {
+        if let Some(body) = body.as_object_mut() {
+            if let Some(standard) = &self.standard {
+                body.insert("standard".to_owned(), json!(standard));
+            }
+            if let Some(runners) = &self.runners {
+                body.insert("runners".to_owned(), json!(runners));
+            }
+            if let Some(ignore) = &self.ignore {
+                body.insert("ignore".to_owned(), json!(ignore));
+            }
+        }
+    }
+
+    // Record the options on every mapped row, unset options are null
+    pub fn annotate(&self, rows: &mut JsonValue) {
+        match rows {
+            JsonValue::Array(rows) => rows.iter_mut().for_each(|row| self.annotate(row)),
+            JsonValue::Object(row) => {
+                row.insert("standard".to_owned(), json!(self.standard));
+                row.insert(
+                    "runners".to_owned(),
+                    json!(self.runners.as_ref().map(|r| r.join(","))),
+                );
+                row.insert(
+                    "ignore".to_owned(),
+                    json!(self.ignore.as_ref().map(|i| i.join(","))),
+                );
+            }
+            _ => {}
+        }
+    }
 }
 
 #[post("/scan", data = "<raw_data>")]
 pub fn catch_scan(
     raw_data: Data,
-    _key: ApiKey,
+    registry: State<JobRegistry>,
+    caller: Caller,
 ) -> Result<
-    rocket::response::content::Json<String>,
+    status::Custom<rocket::response::content::Json<String>>,
     rocket::response::status::Custom<std::string::String>,
 > {
+    // Read the request body, limited to 1 MB, and check the signature of signed requests
+    let mut buf = Vec::new();
+    raw_data
+        .open()
+        .take(1024 * 1024)
+        .read_to_end(&mut buf)
+        .map_err(|e| {
+            status::Custom(
+                Status::BadRequest,
+                format!("failed to read body data: {}", e),
+            )
+        })?;
+    caller.verify_body(&buf)?;
+
+    // Make sure the caller is allowed to run scans
+    caller.require("scan:run")?;
+
     // Parse incoming request body into a ScanData struct
-    let data: ScanData = serde_json::from_reader(BufReader::new(raw_data.open().take(1024 * 1024)))
+    let body: serde_json::Map<String, JsonValue> = serde_json::from_slice(&buf).map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            format!("failed to parse body data: {}", e),
+        )
+    })?;
+    let run_async = jobs::wants_async(&body);
+    let data: ScanData = serde_json::from_value(JsonValue::Object(body)).map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            format!("failed to parse body data: {}", e),
+        )
+    })?;
+    data.validate()?;
+
+    // With "async": true the scan runs as a background job (see jobs.rs)
+    if run_async {
+        let job = registry.spawn(&caller, "scan", Some(data.url.clone()), 1, |_| {
+            run_scan(data)
+        })?;
+        return Ok(accepted(&job));
+    }
+
+    // Run the scan and return the response as a JSON string
+    Ok(status::Custom(
+        Status::Ok,
+        rocket::response::content::Json(run_scan(data)?.to_string()),
+    ))
+}
+
+// The 202 Accepted response to a request that started a job
+pub(crate) fn accepted(job: &Job) -> status::Custom<rocket::response::content::Json<String>> {
+    status::Custom(
+        Status::Accepted,
+        rocket::response::content::Json(jobs::accepted(job)),
+    )
+}
+
+// The result of a batch entry, either the mapped scan or why it failed
+#[derive(Serialize)]
+struct BatchResult {
+    url: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<JsonValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// The body of a batch request
+#[derive(Deserialize)]
+struct BatchData {
+    scans: Vec<ScanData>,
+    concurrency: Option<usize>,
+    #[serde(default, rename = "async")]
+    run_async: bool,
+}
+
+// The rows of a scan for the issues and crawls tables, and the mapped result
+struct ScanRows {
+    issues: JsonValue,
+    crawls: JsonValue,
+    result: JsonValue,
+    cached: bool,
+}
+
+#[post("/scan/batch", data = "<raw_data>")]
+pub fn catch_scan_batch(
+    raw_data: Data,
+    registry: State<JobRegistry>,
+    caller: Caller,
+) -> Result<
+    status::Custom<rocket::response::content::Json<String>>,
+    rocket::response::status::Custom<std::string::String>,
+> {
+    // Read the request body, limited to 1 MB, and check the signature of signed requests
+    let mut buf = Vec::new();
+    raw_data
+        .open()
+        .take(1024 * 1024)
+        .read_to_end(&mut buf)
         .map_err(|e| {
             status::Custom(
                 Status::BadRequest,
-                format!("failed to parse body data: {}", e),
+                format!("failed to read body data: {}", e),
             )
         })?;
+    caller.verify_body(&buf)?;
 
-    // Create a new reqwest Client
-    let client = Client::new();
+    // Make sure the caller is allowed to run scans
+    caller.require("scan:run")?;
 
-    // Creating the json data for the request
-    let json_data = json!({
-        "url": data.url,
-        "pageInsights": data.page_insights,
-    });
+    // Parse incoming request body into a list of ScanData structs
+    let data: BatchData = serde_json::from_slice(&buf).map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            format!("failed to parse body data: {}", e),
+        )
+    })?;
+    let max = get_env_or("SCAN_BATCH_MAX", 100);
+    if data.scans.is_empty() || data.scans.len() > max {
+        return Err(status::Custom(
+            Status::BadRequest,
+            format!("a batch must hold between 1 and {} scans", max),
+        ));
+    }
+    let concurrency = data
+        .concurrency
+        .unwrap_or_else(|| get_env_or("SCAN_CONCURRENCY", 4));
+    if concurrency == 0 {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "concurrency must be a positive integer".to_owned(),
+        ));
+    }
 
-    // Create a new tokio Runtime to handle the async calls
+    // With "async": true the batch runs as a background job (see jobs.rs)
+    if data.run_async {
+        let total = data.scans.len();
+        let job = registry.spawn(&caller, "scan", None, total, move |job| {
+            run_batch(data.scans, concurrency, Some(job))
+        })?;
+        return Ok(accepted(&job));
+    }
+
+    Ok(status::Custom(
+        Status::Ok,
+        rocket::response::content::Json(run_batch(data.scans, concurrency, None)?.to_string()),
+    ))
+}
+
+// Runs the scans of a batch and stores their rows, as a job it reports progress and failed URLs
+pub fn run_batch(
+    scans: Vec<ScanData>,
+    concurrency: usize,
+    job: Option<&Job>,
+) -> Result<JsonValue, rocket::response::status::Custom<std::string::String>> {
+    // Run the scans with one client and runtime, a failed scan does not stop the others
+    let client = Client::new();
     let rt = tokio::runtime::Runtime::new().unwrap();
     let _guard = rt.enter();
+    let outcomes: Vec<(&ScanData, Result<ScanRows, String>)> = rt.block_on(
+        stream::iter(scans.iter())
+            .map(|scan| {
+                let client = &client;
+                async move {
+                    // A fresh cached result has no rows to store
+                    let rows = match (scan.validate(), cache::lookup(scan)) {
+                        (Err(e), _) => Err(e.1),
+                        (Ok(()), Some(result)) => Ok(ScanRows {
+                            issues: JsonValue::Null,
+                            crawls: JsonValue::Null,
+                            result,
+                            cached: true,
+                        }),
+                        (Ok(()), None) => scan_page(client, scan).await.map_err(|e| e.1),
+                    };
+                    if let Some(job) = job {
+                        if let Err(error) = &rows {
+                            job.target_error(&scan.url, error);
+                        }
+                        job.advance();
+                    }
+                    (scan, rows)
+                }
+            })
+            .buffered(concurrency)
+            .collect(),
+    );
 
-    // Send the request and parse the response
-    let future = rt
-        .block_on(
-            client
-                .post(format!(
-                    "{}/scan",
-                    // Get the A11Y_URL environment variable and append "/scan" to the end
-                    get_env("A11Y_URL")
-                        .map_err(|e| { status::Custom(Status::InternalServerError, e,) })?
-                ))
-                .header("Accept", "application/json")
-                .header("Content-Type", "application/json")
-                .header(
-                    // Get the A11Y_JWT environment variable and set as Authorization header
-                    "Authorization",
-                    get_env("A11Y_JWT")
-                        .map_err(|e| status::Custom(Status::InternalServerError, e))?,
-                )
-                .body(json_data.to_string())
-                .timeout(Duration::from_secs(600)) // Wait up to 15 minutes
-                .send(),
-        )
+    // Store the rows of every successful scan in one insert per table
+    let mut issues = Vec::new();
+    let mut crawls = Vec::new();
+    let mut results = Vec::new();
+    let mut fresh = Vec::new();
+    for (scan, rows) in outcomes {
+        match rows {
+            Ok(rows) => {
+                issues.extend(rows_of(rows.issues));
+                crawls.extend(rows_of(rows.crawls));
+                if !rows.cached {
+                    fresh.push((scan, results.len()));
+                }
+                results.push(BatchResult {
+                    url: scan.url.clone(),
+                    success: true,
+                    result: Some(rows.result),
+                    error: None,
+                });
+            }
+            Err(error) => results.push(BatchResult {
+                url: scan.url.clone(),
+                success: false,
+                result: None,
+                error: Some(error),
+            }),
+        }
+    }
+    for (table, rows) in [("issues", issues), ("crawls", crawls)] {
+        if rows.is_empty() {
+            continue;
+        }
+        rt.block_on(bq_store(
+            "rusty_a11y".to_owned(),
+            table.to_owned(),
+            &JsonValue::Array(rows),
+        ))
         .map_err(|e| {
             status::Custom(
                 Status::InternalServerError,
-                format!("Error sending request: {}", e),
+                format!(
+                    "Error: The scan module failed to store data in google big query: {}",
+                    e
+                ),
             )
-        })?
-        .json::<JsonValue>();
+        })?;
+    }
 
-    // Parse response as JSON
-    let response = rt.block_on(future).map_err(|e| {
-        status::Custom(
-            Status::InternalServerError,
-            format!("Error parsing response: {}", e),
-        )
-    })?;
+    // Cache the results of the scans that were just stored
+    for (scan, i) in fresh {
+        if let Some(result) = results[i].result.take() {
+            results[i].result = Some(cache::insert(scan, result));
+        }
+    }
 
-    // Check for any error in the response
-    check_for_error(&response)?;
+    Ok(serde_json::to_value(&results).unwrap())
+}
 
-    // Create JSON mappers to map the response to the appropriate JSON structure for storage in BigQuery
-    let mapper_bq_issues =
-        JsonMapper::new(serde_json::from_str(include_str!("../mapping/bq_issues.json")).unwrap());
-    let mapper_bq =
-        JsonMapper::new(serde_json::from_str(include_str!("../mapping/bq_crawls.json")).unwrap());
-    let mapper =
-        JsonMapper::new(serde_json::from_str(include_str!("../mapping/crawls.json")).unwrap());
+// Turn mapped data into a list of rows
+fn rows_of(data: JsonValue) -> Vec<JsonValue> {
+    match data {
+        JsonValue::Array(rows) => rows,
+        JsonValue::Null => Vec::new(),
+        row => vec![row],
+    }
+}
 
-    // Map the response to the JSON structure for BigQuery and store in BigQuery
-    let result_bq_issues = mapper_bq_issues.map(&response).map_err(|e| {
-        status::Custom(
+// Sends the scan data to A11yWatch and maps the response data before storing it in Google BigQuery
+pub fn run_scan(
+    data: ScanData,
+) -> Result<JsonValue, rocket::response::status::Custom<std::string::String>> {
+    // Create a new reqwest Client
+    let client = Client::new();
+
+    // Create a new tokio Runtime to handle the async calls
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let _guard = rt.enter();
+
+    // Serve a fresh cached result unless the scan is forced (see cache.rs)
+    if let Some(result) = cache::lookup(&data) {
+        return Ok(result);
+    }
+
+    let rows = rt.block_on(scan_page(&client, &data))?;
+
+    // Store the issues and crawls rows in BigQuery
+    for (table, rows) in [("issues", &rows.issues), ("crawls", &rows.crawls)] {
+        rt.block_on(bq_store("rusty_a11y".to_owned(), table.to_owned(), rows))
+            .map_err(|e| {
+                status::Custom(
+                    Status::InternalServerError,
+                    format!(
+                        "Error: The scan module failed to store data in google big query: {}",
+                        e
+                    ),
+                )
+            })?;
+    }
+
+    Ok(cache::insert(&data, rows.result))
+}
+
+// Sends the scan data to A11yWatch and maps the response to the rows of the issues and crawls tables
+async fn scan_page(
+    client: &Client,
+    data: &ScanData,
+) -> Result<ScanRows, rocket::response::status::Custom<std::string::String>> {
+    // Creating the json data for the request
+    let mut json_data = json!({
+        "url": data.url,
+        "pageInsights": data.page_insights,
+    });
+    data.options.apply(&mut json_data);
+    if let Some(auth) = &data.auth {
+        auth.apply(&data.url, &mut json_data)?;
+    }
+
+    // Send the request to A11yWatch and read the page of the response (see scanner.rs)
+    let scanner = A11yWatch {
+        endpoint: "scan",
+        body: json_data,
+    };
+    let pages = scanner.pages(client).await.map_err(|e| match e {
+        ScanError::A11y(A11yError::Config(e)) => status::Custom(Status::InternalServerError, e),
+        ScanError::A11y(A11yError::Login(e)) => status::Custom(
             Status::InternalServerError,
-            format!("Error: The scan module failed to map json data: {:?}", e),
-        )
-    })?;
-    rt.block_on(bq_store(
-        "rusty_a11y".to_owned(),
-        "issues".to_owned(),
-        &result_bq_issues,
-    ))
-    .map_err(|e| {
-        status::Custom(
+            format!("Error logging in to A11yWatch: {}", e),
+        ),
+        ScanError::A11y(A11yError::Unauthorized) => status::Custom(
             Status::InternalServerError,
-            format!(
-                "Error: The scan module failed to store data in google big query: {}",
-                e
-            ),
-        )
-    })?;
-
-    // Map the response to the JSON structure for BigQuery and store in BigQuery for crawls
-    let result_bq = mapper_bq.map(&response).map_err(|e| {
-        status::Custom(
+            "Error: A11yWatch rejected the JWT".to_owned(),
+        ),
+        ScanError::A11y(A11yError::Request(e)) => status::Custom(
             Status::InternalServerError,
-            format!("Error: The scan module failed to map json data: {:?}", e),
-        )
-    })?;
-    rt.block_on(bq_store(
-        "rusty_a11y".to_owned(),
-        "crawls".to_owned(),
-        &result_bq,
-    ))
-    .map_err(|e| {
-        status::Custom(
+            format!("Error sending request: {}", e),
+        ),
+        ScanError::A11y(A11yError::Response(e)) => status::Custom(
             Status::InternalServerError,
-            format!(
-                "Error: The scan module failed to store data in google big query: {}",
-                e
-            ),
-        )
+            format!("Error parsing response: {}", e),
+        ),
+        // Check for any error in the response
+        ScanError::Failed(e) => e,
+        ScanError::Invalid(e) => status::Custom(
+            Status::InternalServerError,
+            format!("Error parsing response: {}", e),
+        ),
     })?;
 
-    // Map the response to the JSON structure and return a JSON object
-    let result = mapper.map(&response).map_err(|e| {
+    // Map the page to the JSON structure for the issues and crawls tables and the one returned to the client,
+    // with the fingerprints and suppressions of the issues
+    let Mapped {
+        issues,
+        crawls,
+        result,
+    } = map_pages(scanner.engine(), &pages, false, &data.options).map_err(|e| {
         status::Custom(
             Status::InternalServerError,
             format!("Error: The scan module failed to map json data: {:?}", e),
         )
     })?;
 
-    Ok(rocket::response::content::Json(result.to_string()))
+    Ok(ScanRows {
+        issues,
+        crawls,
+        result,
+        cached: false,
+    })
 }
@@ -0,0 +1,658 @@
+use crate::a11ywatch::{self, A11yError};
+use crate::fingerprint::add_fingerprints;
+use crate::map_json::{JsonMapper, JsonMapperError};
+use crate::scan::ScanOptions;
+use crate::suppress;
+use crate::util::check_for_error;
+use chrono::{SecondsFormat, Utc};
+use reqwest::{Client, Url};
+use rocket::response::status;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value as JsonValue};
+
+/*
+Code Summary:
+This module puts the accessibility engines behind one Scanner trait, so the mapping, storage, fingerprints, suppressions and diffs work the same whichever engine found the issues. A Scanner returns the scanned pages in one issue model, the Page and Issue below. The model is the shape of the A11yWatch response, so the mapping files read the pages of every engine as they read a response of A11yWatch.
+
+There are three scanners:
+    A11yWatch :
+        Sends a scan or crawl to the A11yWatch API (see a11ywatch.rs). Used by /scan, /scan/batch, /scan/sitemap and /crawl.
+    Pa11yReport :
+        Reads a report of pa11y ("--reporter json", one result or a list of them) or of pa11y-ci ("--json"). The issues keep the code, type, typeCode, runner, selector and context pa11y gives them.
+    AxeReport :
+        Reads the result of axe-core (axe.run, one result or a list of them as written by the axe CLI). Every node of a violation becomes an error and every node of an incomplete result a warning, as pa11y does with its axe runner. The code is the rule id, the message the help text and link of the rule, the selector the target of the node and the context its html.
+
+Imported pages have no accessScore, since only A11yWatch computes one, and their lastScanDate is the timestamp of the axe result or the time of the import for pa11y.
+
+Variables:
+    Scanner :
+        The trait of an engine: its name and the pages it scanned.
+    Page :
+        One scanned page: url, domain, lastScanDate, online, pageLoadTime, the issues and their counts in issuesInfo.
+    Issue :
+        One issue: type (error, warning or notice), typeCode (1, 2 or 3), code, message, context, selector, runner and recurrence.
+    ScanError :
+        Why a scanner returned no pages.
+    Mapped :
+        The mapped rows of the pages for the issues and crawls tables and the result returned to the client.
+
+Functions:
+    map_pages :
+        Maps pages to the issues and crawls rows and the result, records the engine and options on the rows, adds the fingerprints and flags the suppressed issues.
+    response :
+        The pages as a response of A11yWatch, the input of the mapping files.
+    Page::new :
+        A page of an imported report, with the issue counts computed from its issues.
+    A11yWatch::pages :
+        Posts the body to the endpoint of A11yWatch, checks the response and reads the pages of its data.
+    Pa11yReport::pages / AxeReport::pages :
+        Read the pages of a report. A page without a url gets the url of the request.
+
+Docker Vars:
+    A11Y_URL, A11Y_JWT, A11Y_EMAIL, A11Y_PASSWORD :
+        Used by the A11yWatch scanner, see a11ywatch.rs.
+
+Output:
+    Every issues and crawls row gets an "engine" column with "a11ywatch", "pa11y" or "axe".
+
+Errors:
+    ScanError::A11y :
+        Talking to A11yWatch failed, see a11ywatch.rs.
+    ScanError::Failed :
+        A11yWatch answered without success, 424 Failed Dependency (see util.rs).
+    ScanError::Invalid :
+        The response or report could not be read as pages. The callers answer 500 for A11yWatch and 400 for an imported report.
+*/
+
+pub trait Scanner {
+    // The name of the engine, stored in the engine column
+    fn engine(&self) -> &'static str;
+
+    // The pages scanned, in the common issue model
+    async fn pages(&self, client: &Client) -> Result<Vec<Page>, ScanError>;
+}
+
+#[derive(Debug)]
+pub enum ScanError {
+    A11y(A11yError),
+    Failed(status::Custom<String>),
+    Invalid(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Page {
+    #[serde(default, deserialize_with = "nullable")]
+    pub url: String,
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub last_scan_date: Option<String>,
+    #[serde(default)]
+    pub online: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_load_time: Option<PageLoadTime>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub issues: Vec<Issue>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub issues_info: IssuesInfo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PageLoadTime {
+    #[serde(default)]
+    pub duration: Option<JsonValue>,
+    #[serde(default)]
+    pub duration_formated: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IssuesInfo {
+    #[serde(default, deserialize_with = "nullable")]
+    pub total_issues: u64,
+    #[serde(default, deserialize_with = "nullable")]
+    pub error_count: u64,
+    #[serde(default, deserialize_with = "nullable")]
+    pub warning_count: u64,
+    #[serde(default, deserialize_with = "nullable")]
+    pub notice_count: u64,
+    #[serde(default)]
+    pub access_score: Option<serde_json::Number>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Issue {
+    #[serde(default, rename = "type", deserialize_with = "nullable")]
+    pub issue_type: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub type_code: u8,
+    #[serde(default, deserialize_with = "nullable")]
+    pub code: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub message: String,
+    #[serde(default)]
+    pub context: Option<String>,
+    #[serde(default)]
+    pub selector: Option<String>,
+    #[serde(default)]
+    pub runner: Option<String>,
+    #[serde(default)]
+    pub recurrence: Option<u64>,
+}
+
+// A null is read as the default, A11yWatch sends null for what it has not measured
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+// The mapped rows of some pages and the result returned to the client
+pub struct Mapped {
+    pub issues: JsonValue,
+    pub crawls: JsonValue,
+    pub result: JsonValue,
+}
+
+impl Issue {
+    // An issue of an imported report, the typeCode follows from the type
+    fn new(issue_type: &str, code: &str, message: &str, runner: &str) -> Self {
+        let type_code = match issue_type {
+            "error" => 1,
+            "warning" => 2,
+            _ => 3,
+        };
+        Issue {
+            issue_type: issue_type.to_owned(),
+            type_code,
+            code: code.to_owned(),
+            message: message.to_owned(),
+            runner: Some(runner.to_owned()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Page {
+    // A page of an imported report, counting its issues by type
+    pub fn new(url: &str, last_scan_date: Option<String>, issues: Vec<Issue>) -> Self {
+        let count = |type_code| issues.iter().filter(|i| i.type_code == type_code).count() as u64;
+        let issues_info = IssuesInfo {
+            total_issues: issues.len() as u64,
+            error_count: count(1),
+            warning_count: count(2),
+            notice_count: count(3),
+            access_score: None,
+        };
+        Page {
+            url: url.to_owned(),
+            domain: Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned)),
+            last_scan_date: Some(
+                last_scan_date
+                    .unwrap_or_else(|| Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+            ),
+            online: Some(true),
+            page_load_time: None,
+            issues,
+            issues_info,
+        }
+    }
+}
+
+// The pages as a response of A11yWatch, a list of responses for a crawl or import
+pub fn response(pages: &[Page], list: bool) -> JsonValue {
+    match (pages, list) {
+        ([page], false) => json!({"success": true, "data": page}),
+        _ => JsonValue::Array(
+            pages
+                .iter()
+                .map(|page| json!({"success": true, "data": page}))
+                .collect(),
+        ),
+    }
+}
+
+// Map the pages to the rows of the issues and crawls tables and the result returned to the client
+pub fn map_pages(
+    engine: &str,
+    pages: &[Page],
+    list: bool,
+    options: &ScanOptions,
+) -> Result<Mapped, JsonMapperError> {
+    let response = response(pages, list);
+    let mapper_bq_issues =
+        JsonMapper::new(serde_json::from_str(include_str!("../mapping/bq_issues.json")).unwrap());
+    let mapper_bq =
+        JsonMapper::new(serde_json::from_str(include_str!("../mapping/bq_crawls.json")).unwrap());
+    let mapper =
+        JsonMapper::new(serde_json::from_str(include_str!("../mapping/crawls.json")).unwrap());
+
+    let mut issues = mapper_bq_issues.map(&response)?;
+    let mut crawls = mapper_bq.map(&response)?;
+    for rows in [&mut issues, &mut crawls] {
+        options.annotate(rows);
+        record_engine(rows, engine);
+    }
+    add_fingerprints(&mut issues);
+
+    // Flag the suppressed issues and take them out of the counts of every page (see suppress.rs)
+    let mut result = mapper.map(&response)?;
+    suppress::apply(&mut issues, &mut crawls, &mut result);
+    Ok(Mapped {
+        issues,
+        crawls,
+        result,
+    })
+}
+
+// Record the engine on every mapped row
+fn record_engine(rows: &mut JsonValue, engine: &str) {
+    match rows {
+        JsonValue::Array(rows) => rows.iter_mut().for_each(|row| record_engine(row, engine)),
+        JsonValue::Object(row) => {
+            row.insert("engine".to_owned(), json!(engine));
+        }
+        _ => {}
+    }
+}
+
+// A scan or crawl by the A11yWatch API
+pub struct A11yWatch {
+    pub endpoint: &'static str,
+    pub body: JsonValue,
+}
+
+impl Scanner for A11yWatch {
+    fn engine(&self) -> &'static str {
+        "a11ywatch"
+    }
+
+    async fn pages(&self, client: &Client) -> Result<Vec<Page>, ScanError> {
+        let response = a11ywatch::post(client, self.endpoint, &self.body)
+            .await
+            .map_err(ScanError::A11y)?;
+        check_for_error(&response).map_err(ScanError::Failed)?;
+
+        // The data is a page or a list of pages, a crawl may answer a list of responses
+        let responses = match response {
+            JsonValue::Array(responses) => responses,
+            response => vec![response],
+        };
+        let mut pages = Vec::new();
+        for mut response in responses {
+            match response.get_mut("data").map(JsonValue::take) {
+                Some(JsonValue::Array(data)) => pages.extend(data),
+                Some(data) => pages.push(data),
+                None => {}
+            }
+        }
+        pages
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()
+            .map_err(|e| ScanError::Invalid(e.to_string()))
+    }
+}
+
+// A report of pa11y or pa11y-ci
+pub struct Pa11yReport {
+    pub report: JsonValue,
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Pa11yResult {
+    page_url: Option<String>,
+    #[serde(default)]
+    issues: Vec<Pa11yIssue>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Pa11yIssue {
+    code: Option<String>,
+    #[serde(rename = "type")]
+    issue_type: Option<String>,
+    message: Option<String>,
+    context: Option<String>,
+    selector: Option<String>,
+    runner: Option<String>,
+}
+
+// pa11y-ci lists the issues of every URL, or the error that stopped the test of a URL
+#[derive(Deserialize)]
+struct Pa11yCiReport {
+    results: serde_json::Map<String, JsonValue>,
+}
+
+impl Pa11yReport {
+    fn page(url: &str, issues: Vec<Pa11yIssue>) -> Page {
+        let mut online = true;
+        let issues = issues
+            .into_iter()
+            .filter_map(|issue| {
+                // An entry without a code is the error of a page that could not be tested
+                let code = match issue.code {
+                    Some(code) => code,
+                    None => {
+                        online = false;
+                        return None;
+                    }
+                };
+                let mut normalized = Issue::new(
+                    issue.issue_type.as_deref().unwrap_or("notice"),
+                    &code,
+                    issue.message.as_deref().unwrap_or_default(),
+                    issue.runner.as_deref().unwrap_or("htmlcs"),
+                );
+                normalized.context = issue.context;
+                normalized.selector = issue.selector;
+                Some(normalized)
+            })
+            .collect();
+        let mut page = Page::new(url, None, issues);
+        page.online = Some(online);
+        page
+    }
+}
+
+impl Scanner for Pa11yReport {
+    fn engine(&self) -> &'static str {
+        "pa11y"
+    }
+
+    async fn pages(&self, _client: &Client) -> Result<Vec<Page>, ScanError> {
+        let invalid = |e: serde_json::Error| ScanError::Invalid(e.to_string());
+        if self.report.get("results").is_some() {
+            let report: Pa11yCiReport =
+                serde_json::from_value(self.report.clone()).map_err(invalid)?;
+            return report
+                .results
+                .into_iter()
+                .map(|(url, issues)| {
+                    let issues = serde_json::from_value(issues).map_err(invalid)?;
+                    Ok(Self::page(&url, issues))
+                })
+                .collect();
+        }
+        let results: Vec<Pa11yResult> = match &self.report {
+            JsonValue::Array(_) => serde_json::from_value(self.report.clone()),
+            _ => serde_json::from_value(self.report.clone()).map(|result| vec![result]),
+        }
+        .map_err(invalid)?;
+        results
+            .into_iter()
+            .map(|result| {
+                let url = page_url(result.page_url, &self.url)?;
+                Ok(Self::page(&url, result.issues))
+            })
+            .collect()
+    }
+}
+
+// A result of axe-core
+pub struct AxeReport {
+    pub report: JsonValue,
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AxeResult {
+    url: Option<String>,
+    timestamp: Option<String>,
+    #[serde(default)]
+    violations: Vec<AxeRule>,
+    #[serde(default)]
+    incomplete: Vec<AxeRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AxeRule {
+    id: String,
+    #[serde(default)]
+    help: String,
+    help_url: Option<String>,
+    #[serde(default)]
+    nodes: Vec<AxeNode>,
+}
+
+#[derive(Deserialize)]
+struct AxeNode {
+    html: Option<String>,
+    #[serde(default)]
+    target: Vec<JsonValue>,
+}
+
+impl AxeNode {
+    // The target of a node in a frame or shadow root is a list of selectors, outermost first
+    fn selector(&self) -> String {
+        self.target
+            .iter()
+            .map(|part| match part {
+                JsonValue::String(selector) => selector.clone(),
+                JsonValue::Array(parts) => parts
+                    .iter()
+                    .filter_map(|part| part.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                part => part.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Scanner for AxeReport {
+    fn engine(&self) -> &'static str {
+        "axe"
+    }
+
+    async fn pages(&self, _client: &Client) -> Result<Vec<Page>, ScanError> {
+        let results: Vec<AxeResult> = match &self.report {
+            JsonValue::Array(_) => serde_json::from_value(self.report.clone()),
+            _ => serde_json::from_value(self.report.clone()).map(|result| vec![result]),
+        }
+        .map_err(|e| ScanError::Invalid(e.to_string()))?;
+        results
+            .into_iter()
+            .map(|result| {
+                let url = page_url(result.url, &self.url)?;
+                let mut issues = Vec::new();
+                for (issue_type, rules) in [
+                    ("error", &result.violations),
+                    ("warning", &result.incomplete),
+                ] {
+                    for rule in rules {
+                        let message = match &rule.help_url {
+                            Some(help_url) => format!("{} ({})", rule.help, help_url),
+                            None => rule.help.clone(),
+                        };
+                        for node in &rule.nodes {
+                            let mut issue = Issue::new(issue_type, &rule.id, &message, "axe");
+                            issue.context = node.html.clone();
+                            issue.selector = Some(node.selector());
+                            issues.push(issue);
+                        }
+                    }
+                }
+                Ok(Page::new(&url, result.timestamp, issues))
+            })
+            .collect()
+    }
+}
+
+// The url of a page of a report, or the url of the request if the report has none
+fn page_url(url: Option<String>, fallback: &Option<String>) -> Result<String, ScanError> {
+    url.filter(|url| !url.is_empty())
+        .or_else(|| fallback.clone())
+        .ok_or_else(|| ScanError::Invalid("a page of the report has no url".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AxeReport, Pa11yReport, Page, ScanError, Scanner};
+    use reqwest::Client;
+    use serde_json::json;
+
+    fn pages(scanner: &impl Scanner) -> Result<Vec<Page>, ScanError> {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(scanner.pages(&Client::new()))
+    }
+
+    #[test]
+    fn pa11y_results_are_normalized() {
+        let report = Pa11yReport {
+            report: json!({
+                "pageUrl": "https://example.gov/",
+                "issues": [
+                    {"code": "WCAG2AA.Principle1.Guideline1_1.1_1_1.H37", "type": "error",
+                     "message": "Img element missing an alt attribute.",
+                     "context": "<img src=\"logo.png\">", "selector": "#logo > img", "runner": "htmlcs"},
+                    {"code": "color-contrast", "type": "warning", "message": "Low contrast",
+                     "context": null, "selector": null, "runner": "axe"},
+                    {"code": "WCAG2AA.Principle3.Guideline3_1.3_1_1.H57.2", "type": "notice", "message": "Lang"}
+                ]
+            }),
+            url: None,
+        };
+        let pages = pages(&report).unwrap();
+        assert_eq!(pages.len(), 1);
+        let page = &pages[0];
+        assert_eq!(page.url, "https://example.gov/");
+        assert_eq!(page.domain.as_deref(), Some("example.gov"));
+        assert_eq!(page.online, Some(true));
+        assert_eq!(page.issues_info.total_issues, 3);
+        assert_eq!(page.issues_info.error_count, 1);
+        assert_eq!(page.issues_info.warning_count, 1);
+        assert_eq!(page.issues_info.notice_count, 1);
+        assert_eq!(page.issues[0].type_code, 1);
+        assert_eq!(page.issues[0].selector.as_deref(), Some("#logo > img"));
+        assert_eq!(page.issues[1].runner.as_deref(), Some("axe"));
+        assert_eq!(page.issues[2].runner.as_deref(), Some("htmlcs"));
+    }
+
+    #[test]
+    fn pa11y_ci_marks_failed_pages_offline() {
+        let report = Pa11yReport {
+            report: json!({
+                "total": 2,
+                "results": {
+                    "https://example.gov/": [
+                        {"code": "H37", "type": "error", "message": "Missing alt"}
+                    ],
+                    "https://example.gov/down": [
+                        {"message": "net::ERR_CONNECTION_REFUSED"}
+                    ]
+                }
+            }),
+            url: None,
+        };
+        let pages = pages(&report).unwrap();
+        assert_eq!(pages.len(), 2);
+        let up = pages
+            .iter()
+            .find(|p| p.url == "https://example.gov/")
+            .unwrap();
+        assert_eq!(up.online, Some(true));
+        assert_eq!(up.issues_info.error_count, 1);
+        let down = pages
+            .iter()
+            .find(|p| p.url == "https://example.gov/down")
+            .unwrap();
+        assert_eq!(down.online, Some(false));
+        assert!(down.issues.is_empty());
+    }
+
+    #[test]
+    fn pa11y_needs_a_page_url() {
+        let report = json!([{"issues": []}]);
+        let missing = Pa11yReport {
+            report: report.clone(),
+            url: None,
+        };
+        assert!(matches!(pages(&missing), Err(ScanError::Invalid(_))));
+        let fallback = Pa11yReport {
+            report,
+            url: Some("https://example.gov/".to_owned()),
+        };
+        assert_eq!(pages(&fallback).unwrap()[0].url, "https://example.gov/");
+    }
+
+    #[test]
+    fn axe_results_are_normalized() {
+        let report = AxeReport {
+            report: json!({
+                "url": "https://example.gov/form",
+                "timestamp": "2024-01-02T03:04:05.000Z",
+                "violations": [{
+                    "id": "image-alt",
+                    "help": "Images must have alternate text",
+                    "helpUrl": "https://dequeuniversity.com/rules/axe/4.8/image-alt",
+                    "nodes": [
+                        {"html": "<img src=\"a.png\">", "target": ["#a"]},
+                        {"html": "<img src=\"b.png\">", "target": [["iframe#pay", "img.card"]]}
+                    ]
+                }],
+                "incomplete": [{
+                    "id": "color-contrast",
+                    "help": "Elements must meet minimum color contrast ratio thresholds",
+                    "nodes": [{"html": "<span>Total</span>", "target": [".total"]}]
+                }],
+                "passes": [{"id": "document-title", "nodes": [{"target": ["html"]}]}]
+            }),
+            url: None,
+        };
+        let pages = pages(&report).unwrap();
+        let page = &pages[0];
+        assert_eq!(page.url, "https://example.gov/form");
+        assert_eq!(
+            page.last_scan_date.as_deref(),
+            Some("2024-01-02T03:04:05.000Z")
+        );
+        assert_eq!(page.issues_info.total_issues, 3);
+        assert_eq!(page.issues_info.error_count, 2);
+        assert_eq!(page.issues_info.warning_count, 1);
+        let issue = &page.issues[0];
+        assert_eq!(issue.code, "image-alt");
+        assert_eq!(issue.runner.as_deref(), Some("axe"));
+        assert_eq!(
+            issue.message,
+            "Images must have alternate text (https://dequeuniversity.com/rules/axe/4.8/image-alt)"
+        );
+        assert_eq!(
+            page.issues[1].selector.as_deref(),
+            Some("iframe#pay img.card")
+        );
+        assert_eq!(page.issues[2].issue_type, "warning");
+        assert_eq!(
+            page.issues[2].message,
+            "Elements must meet minimum color contrast ratio thresholds"
+        );
+    }
+
+    #[test]
+    fn axe_lists_one_page_per_result() {
+        let report = AxeReport {
+            report: json!([
+                {"url": "https://example.gov/a", "violations": []},
+                {"url": "", "violations": []}
+            ]),
+            url: Some("https://example.gov/b".to_owned()),
+        };
+        let pages = pages(&report).unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].url, "https://example.gov/a");
+        assert_eq!(pages[1].url, "https://example.gov/b");
+        assert_eq!(pages[1].issues_info.total_issues, 0);
+        assert!(pages[1].last_scan_date.is_some());
+    }
+}
@@ -0,0 +1,304 @@
+use crate::auth::Caller;
+use crate::crawl;
+use crate::get_env;
+use crate::page_auth::PageAuth;
+use crate::scan::{self, ScanData, ScanOptions};
+use crate::up;
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use rocket::{get, State};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/*
+Code Summary:
+This module runs the up cycle, the crawl cycle and single scans on a schedule, so no external cron has to call /up and /crawl. The jobs are read at launch from the JSON file named by SCHEDULE_FILE, each with a name, a cron expression and the action to run:
+
+    [
+        {"name": "up", "cron": "0 0/5 * * * *", "action": "up"},
+        {"name": "nightly crawl", "cron": "0 0 3 * * *", "action": "crawl"},
+        {"name": "home page", "cron": "0 30 6 * * Mon", "action": "scan", "url": "https://example.gov", "page_insights": false, "standard": "WCAG2AA"}
+    ]
+
+Cron expressions have a seconds field: "sec min hour day-of-month month day-of-week [year]", in UTC. Every job runs on its own thread and never overlaps itself: while a run is still going, the times it was due are skipped and counted. The next and last run of every job can be read with GET /schedule.
+
+Variables:
+    Action :
+        What a job runs: the up cycle with the UP_* settings, the crawl cycle, or a scan of one URL with the optional standard, runners and ignore of a /scan body. Scheduled scans are always forced, so they scan the page and store its rows instead of being answered from the scan cache (see cache.rs). A scan can name page credentials of PAGE_AUTH_FILE in auth, inline credentials are not accepted here.
+    JobConfig :
+        One entry of the schedule file.
+    RunState :
+        Whether a job is running, when it last started and finished, the outcome of its last run and how many runs were skipped.
+    Job :
+        A job with its parsed cron schedule and its RunState.
+    Scheduler :
+        All jobs. It is handed to Rocket as managed state.
+    JobStatus :
+        The status of one job as returned by GET /schedule.
+
+Functions:
+    Scheduler::load :
+        Reads and validates the schedule file. Without SCHEDULE_FILE there are no jobs.
+    Scheduler::start :
+        Starts a thread per job that waits for the next due time and runs the job.
+    Job::run :
+        Runs the action of the job and returns a summary of the outcome.
+    catch_schedule :
+        Rocket endpoint for GET /schedule, it needs the "schedule:read" scope.
+
+Docker Vars:
+    SCHEDULE_FILE :
+        Path to the JSON list of jobs.
+
+Output:
+    GET /schedule returns a JSON list of {"name", "action", "cron", "running", "next_run", "last_run", "last_finished", "last_result", "skipped"}.
+
+Errors:
+    failed to read schedule file {}: {} / failed to parse schedule file {}: {} :
+        The schedule file could not be read or is not a list of jobs. The service does not start.
+    invalid scan options for job '{}': {} :
+        A scan job has a standard or runner A11yWatch does not support. The service does not start.
+    invalid page credentials for job '{}': {} :
+        A scan job names page credentials that are not in PAGE_AUTH_FILE. The service does not start.
+    invalid cron expression for job '{}': {} :
+        A job has a cron expression that cannot be parsed. The service does not start.
+*/
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Action {
+    Up,
+    Crawl,
+    Scan {
+        url: String,
+        #[serde(default)]
+        page_insights: bool,
+        #[serde(flatten)]
+        options: ScanOptions,
+        #[serde(default)]
+        auth: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct JobConfig {
+    name: String,
+    cron: String,
+    #[serde(flatten)]
+    action: Action,
+}
+
+#[derive(Default, Clone)]
+struct RunState {
+    running: bool,
+    last_run: Option<DateTime<Utc>>,
+    last_finished: Option<DateTime<Utc>>,
+    last_result: Option<String>,
+    skipped: u64,
+}
+
+struct Job {
+    name: String,
+    cron: String,
+    schedule: Schedule,
+    action: Action,
+    state: Mutex<RunState>,
+}
+
+pub struct Scheduler {
+    jobs: Vec<Arc<Job>>,
+}
+
+#[derive(Serialize)]
+struct JobStatus {
+    name: String,
+    action: &'static str,
+    cron: String,
+    running: bool,
+    next_run: Option<String>,
+    last_run: Option<String>,
+    last_finished: Option<String>,
+    last_result: Option<String>,
+    skipped: u64,
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Up => "up",
+            Action::Crawl => "crawl",
+            Action::Scan { .. } => "scan",
+        }
+    }
+}
+
+impl Job {
+    // Run the action and summarize its outcome
+    fn run(&self) -> String {
+        let result = match &self.action {
+            Action::Up => up::read_settings(&serde_json::Map::new())
+                .and_then(|(concurrency, settings)| up::run_cycle(concurrency, &settings, None)),
+            Action::Crawl => crawl::run_cycle().map(|responses| {
+                format!(
+                    "crawled {} target(s)",
+                    responses.as_array().map_or(0, |r| r.len())
+                )
+            }),
+            Action::Scan {
+                url,
+                page_insights,
+                options,
+                auth,
+            } => scan::run_scan(ScanData {
+                url: url.clone(),
+                page_insights: *page_insights,
+                // A scheduled scan is meant to record the page, never to read the cache
+                force: true,
+                options: options.clone(),
+                auth: auth.clone().map(PageAuth::Named),
+            })
+            .map(|_| format!("scanned {}", url)),
+        };
+        match result {
+            Ok(summary) => summary,
+            Err(e) => format!("failed: {}", e.1),
+        }
+    }
+}
+
+impl Scheduler {
+    // Load the jobs from SCHEDULE_FILE, there are none if it is not set
+    pub fn load() -> Result<Self, String> {
+        let path = match get_env("SCHEDULE_FILE") {
+            Ok(path) => path,
+            Err(_) => return Ok(Scheduler { jobs: Vec::new() }),
+        };
+        let file = std::fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read schedule file {}: {}", path, e))?;
+        let configs: Vec<JobConfig> = serde_json::from_str(&file)
+            .map_err(|e| format!("failed to parse schedule file {}: {}", path, e))?;
+
+        let mut jobs = Vec::new();
+        for config in configs {
+            if let Action::Scan {
+                url, options, auth, ..
+            } = &config.action
+            {
+                options.validate().map_err(|e| {
+                    format!("invalid scan options for job '{}': {}", config.name, e.1)
+                })?;
+                if let Some(auth) = auth {
+                    PageAuth::Named(auth.clone()).validate(url).map_err(|e| {
+                        format!(
+                            "invalid page credentials for job '{}': {}",
+                            config.name, e.1
+                        )
+                    })?;
+                }
+            }
+            let schedule = Schedule::from_str(&config.cron)
+                .map_err(|e| format!("invalid cron expression for job '{}': {}", config.name, e))?;
+            jobs.push(Arc::new(Job {
+                name: config.name,
+                cron: config.cron,
+                schedule,
+                action: config.action,
+                state: Mutex::new(RunState::default()),
+            }));
+        }
+        Ok(Scheduler { jobs })
+    }
+
+    // Start a thread per job that runs it whenever it is due
+    pub fn start(&self) {
+        for job in self.jobs.iter() {
+            let job = job.clone();
+            thread::spawn(move || loop {
+                let next = match job.schedule.upcoming(Utc).next() {
+                    Some(next) => next,
+                    None => return, // The schedule has no more due times
+                };
+                if let Ok(wait) = (next - Utc::now()).to_std() {
+                    thread::sleep(wait);
+                }
+
+                let started = Utc::now();
+                {
+                    let mut state = job.state.lock().unwrap();
+                    state.running = true;
+                    state.last_run = Some(started);
+                }
+                println!("schedule: starting '{}'", job.name);
+
+                // Run on a separate thread so a panic ends the run, not the schedule
+                let worker = job.clone();
+                let summary = thread::spawn(move || worker.run())
+                    .join()
+                    .unwrap_or_else(|_| "failed: the run panicked".to_owned());
+
+                // The times the job was due while it was running are skipped
+                let finished = Utc::now();
+                let skipped = job
+                    .schedule
+                    .after(&started)
+                    .take_while(|due| *due < finished)
+                    .count() as u64;
+                println!(
+                    "schedule: '{}' finished: {}{}",
+                    job.name,
+                    summary,
+                    if skipped > 0 {
+                        format!(
+                            ", skipped {} run(s) that were due while it was running",
+                            skipped
+                        )
+                    } else {
+                        String::new()
+                    }
+                );
+                let mut state = job.state.lock().unwrap();
+                state.running = false;
+                state.last_finished = Some(finished);
+                state.last_result = Some(summary);
+                state.skipped += skipped;
+            });
+        }
+    }
+}
+
+// Report the next and last run of every scheduled job
+#[get("/schedule")]
+pub(crate) fn catch_schedule(
+    scheduler: State<Scheduler>,
+    caller: Caller,
+) -> Result<String, rocket::response::status::Custom<std::string::String>> {
+    // Check the signature of signed requests, a GET has no body
+    caller.verify_body(&[])?;
+    caller.require("schedule:read")?;
+
+    let jobs: Vec<JobStatus> = scheduler
+        .jobs
+        .iter()
+        .map(|job| {
+            let state = job.state.lock().unwrap().clone();
+            JobStatus {
+                name: job.name.clone(),
+                action: job.action.name(),
+                cron: job.cron.clone(),
+                running: state.running,
+                next_run: job.schedule.upcoming(Utc).next().map(format_time),
+                last_run: state.last_run.map(format_time),
+                last_finished: state.last_finished.map(format_time),
+                last_result: state.last_result,
+                skipped: state.skipped,
+            }
+        })
+        .collect();
+    Ok(serde_json::to_string(&jobs).unwrap())
+}
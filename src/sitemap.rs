@@ -0,0 +1,599 @@
+use crate::auth::Caller;
+use crate::get_env_or;
+use crate::jobs::{Job, JobRegistry};
+use crate::page_auth::PageAuth;
+use crate::report::parse_time;
+use crate::scan::{accepted, run_batch, ScanData, ScanOptions};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use quick_xml::events::Event;
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::NsReader;
+use regex::Regex;
+use reqwest::{Client, Url};
+use rocket::{http::Status, post, response::status, Data, State};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::Read;
+use std::time::Duration;
+
+/*
+Code Summary:
+This module scans the pages listed in a site's sitemap, so pages that are not linked from the navigation and that the A11yWatch crawl misses are scanned too. POST /scan/sitemap fetches the sitemap (by default /sitemap.xml of the site), follows sitemap indexes and reads gzip compressed sitemaps. Only sitemaps and pages on the scheme, host and port of the sitemap are read, as the sitemap protocol requires, so a sitemap cannot send the page credentials of the request to another site. A sitemap of an index that cannot be read, is too large or is cut off is reported with the others still read. The URLs can be filtered by include and exclude patterns and by their lastmod. The pages left are scanned like the entries of /scan/batch: SCAN_CONCURRENCY at a time, through the scan cache, and stored in the issues and crawls tables with one insert per table.
+
+Variables:
+    caller :
+        The API key, signed request or bearer token that made the request. It needs the "scan:run" scope.
+    SitemapData :
+        The body of the request: the site url, an optional sitemap URL, the filters and the settings of every scan.
+    Filters :
+        The compiled include and exclude patterns and the lastmod cut-off.
+    Entry :
+        A loc of a sitemap with its lastmod.
+    SitemapPages :
+        What the sitemaps list: the number of page URLs found, those skipped for another scheme or host, the pages that pass the filters and the sitemaps that could not be read with the reason.
+    SITEMAP_NAMESPACES :
+        The namespaces of the sitemap protocol. loc and lastmod elements of other namespaces, such as image:loc, are not read.
+    MAX_SITEMAPS :
+        The most sitemaps read for one request, counting the index.
+    MAX_SITEMAP_BYTES :
+        The largest sitemap read, downloaded or after decompressing, the limit of the sitemap protocol. A larger sitemap is not read.
+
+Functions:
+    catch_scan_sitemap :
+        Rocket endpoint for POST /scan/sitemap. Checks the body, then reads the sitemap and scans its pages, or does so as a background job with "async": true.
+    run_sitemap :
+        Reads the page URLs from the sitemap and runs them through scan::run_batch.
+    read_pages :
+        Fetches the sitemap and the sitemaps of its index on the same site and returns the URLs that pass the filters, and the sitemaps that could not be read.
+    same_site :
+        Whether a URL has the scheme, host and port of the sitemap.
+    fetch_sitemap :
+        Downloads a sitemap a chunk at a time, up to MAX_SITEMAP_BYTES, and decompresses it if it is gzipped.
+    parse_sitemap :
+        Reads the loc and lastmod directly inside every url of a urlset, or every sitemap of a sitemapindex, in the sitemap namespace. A document that ends before its root element is closed is an error.
+    entry_name, entry_field :
+        The element of an entry, and the field of an entry the text being read belongs to.
+    Filters::keep :
+        Whether a URL passes the include, exclude and since filters.
+
+Docker Vars:
+    SCAN_CONCURRENCY :
+        Number of pages scanned at the same time, default 4. Can be overridden per request with "concurrency".
+    SITEMAP_MAX_URLS :
+        The most pages one request may scan after filtering, default 500.
+
+Output:
+    {"sitemap", "found", "skipped", "scanned", "sitemap_errors": [{"sitemap", "error"}], "results": [{"url", "success", "result", "error"}]}
+    found counts the URLs of the sitemaps, skipped those left out for another scheme or host, scanned those that passed the filters. sitemap_errors lists the sitemaps of the index that were not read: they could not be fetched or parsed, were larger than MAX_SITEMAP_BYTES, were on another site, or were past MAX_SITEMAPS. As a job they are also recorded as target errors. results holds one BatchResult per page, as returned by /scan/batch.
+
+Errors:
+    failed to read body data: {} / failed to parse body data: {} :
+        400 if the body could not be read or parsed.
+    invalid {} pattern: {} :
+        400 if an include or exclude pattern is not a valid regular expression.
+    since must be an RFC 3339 timestamp or a date :
+        400 if since could not be parsed.
+    url must be an http or https URL :
+        400 if the site or sitemap URL cannot be parsed.
+    sitemap {} failed to fetch: {} / sitemap {} failed to parse: {} :
+        502 if the sitemap of the request could not be downloaded, decompressed or read, or is larger than MAX_SITEMAP_BYTES. Sitemaps of its index are reported in sitemap_errors instead.
+    the sitemap lists {} matching pages, more than SITEMAP_MAX_URLS ({}) :
+        400 if too many pages pass the filters, narrow them down with include, exclude or since.
+    failed to store data to google big query: {} :
+        500 if the rows could not be stored.
+*/
+
+const MAX_SITEMAPS: usize = 50;
+const MAX_SITEMAP_BYTES: u64 = 50 * 1024 * 1024;
+const SITEMAP_NAMESPACES: [&[u8]; 2] = [
+    b"http://www.sitemaps.org/schemas/sitemap/0.9",
+    b"http://www.google.com/schemas/sitemap/0.84",
+];
+
+#[derive(Deserialize)]
+struct SitemapData {
+    url: String,
+    sitemap: Option<String>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    since: Option<String>,
+    #[serde(default)]
+    page_insights: bool,
+    #[serde(default)]
+    force: bool,
+    concurrency: Option<usize>,
+    #[serde(default, rename = "async")]
+    run_async: bool,
+    #[serde(flatten)]
+    options: ScanOptions,
+    #[serde(default)]
+    auth: Option<PageAuth>,
+}
+
+struct Filters {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    since: Option<DateTime<Utc>>,
+}
+
+struct Entry {
+    loc: String,
+    lastmod: Option<String>,
+}
+
+struct SitemapPages {
+    found: usize,
+    skipped: usize,
+    pages: Vec<String>,
+    errors: Vec<(String, String)>,
+}
+
+impl Filters {
+    // Keep a URL if it matches an include pattern (or there are none), no exclude pattern
+    // and was modified since the cut-off. A URL without a readable lastmod is kept.
+    fn keep(&self, entry: &Entry) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|re| re.is_match(&entry.loc)))
+            && !self.exclude.iter().any(|re| re.is_match(&entry.loc))
+            && match (self.since, entry.lastmod.as_deref().and_then(parse_time)) {
+                (Some(since), Some(lastmod)) => lastmod >= since,
+                _ => true,
+            }
+    }
+}
+
+#[post("/scan/sitemap", data = "<raw_data>")]
+pub fn catch_scan_sitemap(
+    raw_data: Data,
+    registry: State<JobRegistry>,
+    caller: Caller,
+) -> Result<
+    status::Custom<rocket::response::content::Json<String>>,
+    rocket::response::status::Custom<std::string::String>,
+> {
+    // Read the request body, limited to 1 MB, and check the signature of signed requests
+    let mut buf = Vec::new();
+    raw_data
+        .open()
+        .take(1024 * 1024)
+        .read_to_end(&mut buf)
+        .map_err(|e| {
+            status::Custom(
+                Status::BadRequest,
+                format!("failed to read body data: {}", e),
+            )
+        })?;
+    caller.verify_body(&buf)?;
+
+    // Make sure the caller is allowed to run scans
+    caller.require("scan:run")?;
+
+    let data: SitemapData = serde_json::from_slice(&buf).map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            format!("failed to parse body data: {}", e),
+        )
+    })?;
+
+    // Check everything that can be checked before the sitemap is read
+    data.options.validate()?;
+    if let Some(auth) = &data.auth {
+        auth.validate(&data.url)?;
+    }
+    let compile = |kind: &str, patterns: &[String]| {
+        patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                status::Custom(
+                    Status::BadRequest,
+                    format!("invalid {} pattern: {}", kind, e),
+                )
+            })
+    };
+    let since = match &data.since {
+        Some(since) => Some(parse_time(since).ok_or_else(|| {
+            status::Custom(
+                Status::BadRequest,
+                "since must be an RFC 3339 timestamp or a date".to_owned(),
+            )
+        })?),
+        None => None,
+    };
+    let filters = Filters {
+        include: compile("include", &data.include)?,
+        exclude: compile("exclude", &data.exclude)?,
+        since,
+    };
+    let bad_url = || {
+        status::Custom(
+            Status::BadRequest,
+            "url must be an http or https URL".to_owned(),
+        )
+    };
+    let sitemap = match &data.sitemap {
+        Some(sitemap) => Url::parse(sitemap),
+        None => Url::parse(&data.url).and_then(|site| site.join("/sitemap.xml")),
+    }
+    .map_err(|_| bad_url())?;
+    if sitemap.scheme() != "http" && sitemap.scheme() != "https" {
+        return Err(bad_url());
+    }
+    let concurrency = data
+        .concurrency
+        .unwrap_or_else(|| get_env_or("SCAN_CONCURRENCY", 4));
+    if concurrency == 0 {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "concurrency must be a positive integer".to_owned(),
+        ));
+    }
+
+    // With "async": true the sitemap is read and scanned as a background job (see jobs.rs)
+    if data.run_async {
+        let target = Some(sitemap.to_string());
+        let job = registry.spawn(&caller, "sitemap", target, 0, move |job| {
+            run_sitemap(data, sitemap, filters, concurrency, Some(job))
+        })?;
+        return Ok(accepted(&job));
+    }
+
+    let result = run_sitemap(data, sitemap, filters, concurrency, None)?;
+    Ok(status::Custom(
+        Status::Ok,
+        rocket::response::content::Json(result.to_string()),
+    ))
+}
+
+// Read the pages of the sitemap and scan them like a batch
+fn run_sitemap(
+    data: SitemapData,
+    sitemap: Url,
+    filters: Filters,
+    concurrency: usize,
+    job: Option<&Job>,
+) -> Result<serde_json::Value, rocket::response::status::Custom<std::string::String>> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let read = rt.block_on(read_pages(&sitemap, &filters))?;
+    drop(rt);
+
+    let max = get_env_or("SITEMAP_MAX_URLS", 500);
+    if read.pages.len() > max {
+        return Err(status::Custom(
+            Status::BadRequest,
+            format!(
+                "the sitemap lists {} matching pages, more than SITEMAP_MAX_URLS ({})",
+                read.pages.len(),
+                max
+            ),
+        ));
+    }
+    if let Some(job) = job {
+        job.set_total(read.pages.len());
+        for (sitemap, error) in &read.errors {
+            job.target_error(sitemap, error);
+        }
+    }
+
+    // Every page is scanned with the settings of the request
+    let scanned = read.pages.len();
+    let scans = read
+        .pages
+        .into_iter()
+        .map(|url| ScanData {
+            url,
+            page_insights: data.page_insights,
+            force: data.force,
+            options: data.options.clone(),
+            auth: data.auth.clone(),
+        })
+        .collect();
+    let results = if scanned > 0 {
+        run_batch(scans, concurrency, job)?
+    } else {
+        serde_json::json!([])
+    };
+    let errors: Vec<_> = read
+        .errors
+        .iter()
+        .map(|(sitemap, error)| serde_json::json!({"sitemap": sitemap, "error": error}))
+        .collect();
+    Ok(serde_json::json!({
+        "sitemap": sitemap.to_string(),
+        "found": read.found,
+        "skipped": read.skipped,
+        "scanned": scanned,
+        "sitemap_errors": errors,
+        "results": results,
+    }))
+}
+
+// Whether a URL has the scheme, host and port of the sitemap, as the sitemap protocol requires.
+// Pages elsewhere would be scanned with the credentials of the request
+fn same_site(url: &Url, sitemap: &Url) -> bool {
+    url.scheme() == sitemap.scheme()
+        && url.host_str() == sitemap.host_str()
+        && url.port_or_known_default() == sitemap.port_or_known_default()
+}
+
+// Read the sitemap and the sitemaps of its index
+async fn read_pages(
+    sitemap: &Url,
+    filters: &Filters,
+) -> Result<SitemapPages, rocket::response::status::Custom<std::string::String>> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()
+        .unwrap();
+    let mut queue = vec![sitemap.clone()];
+    let mut read = HashSet::new();
+    let mut unread = 0;
+    let mut seen = HashSet::new();
+    let mut result = SitemapPages {
+        found: 0,
+        skipped: 0,
+        pages: Vec::new(),
+        errors: Vec::new(),
+    };
+    while let Some(url) = queue.pop() {
+        if !read.insert(url.clone()) {
+            continue;
+        }
+        if read.len() > MAX_SITEMAPS {
+            unread += 1;
+            continue;
+        }
+        let parsed = match fetch_sitemap(&client, &url).await {
+            Ok(bytes) => parse_sitemap(&bytes).map_err(|e| format!("failed to parse: {}", e)),
+            Err(e) => Err(format!("failed to fetch: {}", e)),
+        };
+        let (index, entries) = match parsed {
+            Ok(parsed) => parsed,
+            // Without the sitemap of the request there is nothing to scan
+            Err(e) if url == *sitemap => {
+                return Err(status::Custom(
+                    Status::BadGateway,
+                    format!("sitemap {} {}", url, e),
+                ))
+            }
+            // A sitemap of the index that cannot be read is reported, the others are still read
+            Err(e) => {
+                result.errors.push((url.to_string(), e));
+                continue;
+            }
+        };
+        for entry in entries {
+            if index {
+                // Entries of an index are sitemaps, relative to the index
+                match url.join(&entry.loc) {
+                    Ok(child) if same_site(&child, sitemap) => queue.push(child),
+                    _ => result.errors.push((
+                        entry.loc,
+                        "not read, not on the scheme and host of the sitemap".to_owned(),
+                    )),
+                }
+            } else {
+                result.found += 1;
+                match Url::parse(&entry.loc) {
+                    Ok(page) if same_site(&page, sitemap) => {
+                        if filters.keep(&entry) && seen.insert(entry.loc.clone()) {
+                            result.pages.push(entry.loc);
+                        }
+                    }
+                    _ => result.skipped += 1,
+                }
+            }
+        }
+    }
+    if unread > 0 {
+        result.errors.push((
+            sitemap.to_string(),
+            format!(
+                "{} sitemap(s) not read, at most {} are read for one request",
+                unread, MAX_SITEMAPS
+            ),
+        ));
+    }
+    Ok(result)
+}
+
+// Download a sitemap, decompressing it if it is gzipped. A sitemap larger than the protocol
+// allows is an error rather than read in part
+async fn fetch_sitemap(client: &Client, sitemap: &Url) -> Result<Vec<u8>, String> {
+    let mut response = client
+        .get(sitemap.clone())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status()));
+    }
+    let too_large = || format!("larger than {} bytes", MAX_SITEMAP_BYTES);
+    if response
+        .content_length()
+        .map_or(false, |length| length > MAX_SITEMAP_BYTES)
+    {
+        return Err(too_large());
+    }
+
+    // Read the body a chunk at a time, so a larger one is never held in memory
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        if (body.len() + chunk.len()) as u64 > MAX_SITEMAP_BYTES {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    // A .gz sitemap is gzipped, recognized by the gzip magic bytes
+    if body.starts_with(&[0x1f, 0x8b]) {
+        let mut bytes = Vec::new();
+        GzDecoder::new(&body[..])
+            .take(MAX_SITEMAP_BYTES + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("failed to decompress: {}", e))?;
+        if bytes.len() as u64 > MAX_SITEMAP_BYTES {
+            return Err(too_large());
+        }
+        return Ok(bytes);
+    }
+    Ok(body)
+}
+
+// The element of an entry: a url of a urlset or a sitemap of a sitemapindex
+fn entry_name(index: bool) -> &'static [u8] {
+    if index {
+        b"sitemap"
+    } else {
+        b"url"
+    }
+}
+
+// The field of an entry a text belongs to, a loc or lastmod directly inside the entry
+fn entry_field(path: &[Option<Vec<u8>>], index: bool) -> Option<&[u8]> {
+    match path {
+        [_, Some(entry), Some(field)]
+            if entry.as_slice() == entry_name(index)
+                && matches!(field.as_slice(), b"loc" | b"lastmod") =>
+        {
+            Some(field)
+        }
+        _ => None,
+    }
+}
+
+// Read the entries of a sitemap, and whether it is a sitemap index
+fn parse_sitemap(bytes: &[u8]) -> Result<(bool, Vec<Entry>), String> {
+    let mut reader = NsReader::from_reader(bytes);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut root: Option<bool> = None;
+    // The local names of the open elements, None for those of other namespaces such as image:loc
+    let mut path: Vec<Option<Vec<u8>>> = Vec::new();
+    let (mut loc, mut lastmod): (Option<String>, Option<String>) = (None, None);
+    let mut entries = Vec::new();
+    loop {
+        let (namespace, event) = reader
+            .read_resolved_event_into(&mut buf)
+            .map_err(|e| e.to_string())?;
+        let in_sitemap = match namespace {
+            ResolveResult::Unbound => true,
+            ResolveResult::Bound(Namespace(namespace)) => SITEMAP_NAMESPACES.contains(&namespace),
+            ResolveResult::Unknown(_) => false,
+        };
+        let text = match event {
+            Event::Start(element) => {
+                let name = in_sitemap.then(|| element.local_name().as_ref().to_vec());
+                if root.is_none() {
+                    root = Some(match name.as_deref() {
+                        Some(b"sitemapindex") => true,
+                        Some(b"urlset") => false,
+                        _ => return Err("not a urlset or sitemapindex".to_owned()),
+                    });
+                }
+                path.push(name);
+                None
+            }
+            Event::Text(text) => Some(text.unescape().map_err(|e| e.to_string())?.into_owned()),
+            Event::CData(text) => Some(String::from_utf8_lossy(&text.into_inner()).into_owned()),
+            Event::End(_) => {
+                // The end of a url or sitemap entry
+                let index = root == Some(true);
+                if path.len() == 2 && path[1].as_deref() == Some(entry_name(index)) {
+                    if let Some(loc) = loc.take() {
+                        entries.push(Entry {
+                            loc: loc.trim().to_owned(),
+                            lastmod: lastmod.take().map(|lastmod| lastmod.trim().to_owned()),
+                        });
+                    }
+                    lastmod = None;
+                }
+                path.pop();
+                None
+            }
+            Event::Eof => break,
+            _ => None,
+        };
+        if let (Some(text), Some(index)) = (text, root) {
+            match entry_field(&path, index) {
+                Some(b"loc") => loc = Some(text),
+                Some(b"lastmod") => lastmod = Some(text),
+                _ => {}
+            }
+        }
+        buf.clear();
+    }
+
+    // A document cut off before its root element is closed would silently lose entries
+    if !path.is_empty() {
+        return Err("the document ends before its root element is closed".to_owned());
+    }
+    match root {
+        Some(index) => Ok((index, entries)),
+        None => Err("not a urlset or sitemapindex".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_sitemap;
+
+    #[test]
+    fn parse_sitemap_reads_urls() {
+        let xml = br#"<?xml version="1.0" encoding="UTF-8"?>
+            <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9"
+                    xmlns:image="http://www.google.com/schemas/sitemap-image/1.1">
+                <url>
+                    <loc> https://example.gov/ </loc>
+                    <lastmod>2023-02-01</lastmod>
+                    <image:image><image:loc>https://cdn.example.net/a.png</image:loc></image:image>
+                </url>
+                <url><loc><![CDATA[https://example.gov/about?a=1&b=2]]></loc></url>
+            </urlset>"#;
+        let (index, entries) = parse_sitemap(xml).unwrap();
+        assert!(!index);
+        let locs: Vec<_> = entries.iter().map(|entry| entry.loc.as_str()).collect();
+        assert_eq!(
+            locs,
+            ["https://example.gov/", "https://example.gov/about?a=1&b=2"]
+        );
+        assert_eq!(entries[0].lastmod.as_deref(), Some("2023-02-01"));
+        assert_eq!(entries[1].lastmod, None);
+    }
+
+    #[test]
+    fn parse_sitemap_reads_indexes() {
+        let xml = br#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+                <sitemap><loc>/sitemap-pages.xml.gz</loc></sitemap>
+            </sitemapindex>"#;
+        let (index, entries) = parse_sitemap(xml).unwrap();
+        assert!(index);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].loc, "/sitemap-pages.xml.gz");
+    }
+
+    #[test]
+    fn parse_sitemap_ignores_foreign_and_nested_locs() {
+        // A loc of another namespace directly in a url, and one nested deeper, are not the page
+        let xml = br#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9" xmlns:x="urn:x">
+                <url><x:loc>https://other.example/</x:loc><loc>https://example.gov/a</loc></url>
+                <url><extra><loc>https://other.example/b</loc></extra></url>
+            </urlset>"#;
+        let (_, entries) = parse_sitemap(xml).unwrap();
+        let locs: Vec<_> = entries.iter().map(|entry| entry.loc.as_str()).collect();
+        assert_eq!(locs, ["https://example.gov/a"]);
+    }
+
+    #[test]
+    fn parse_sitemap_rejects_truncated_and_other_documents() {
+        let truncated = br#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+                <url><loc>https://example.gov/</loc></url>
+                <url><loc>https://exa"#;
+        assert!(parse_sitemap(truncated).is_err());
+        assert!(parse_sitemap(b"<html><body></body></html>").is_err());
+        assert!(parse_sitemap(b"").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use crate::get_env;
+use chrono::{NaiveDate, Utc};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/*
+Code Summary:
+This module suppresses issues that are known false positives or accepted risk, such as the htmlcs "position: fixed" warning (WCAG 1.4.10) on USWDS headers, so they stop cluttering every report. The rules are read at launch from the JSON file named by SUPPRESSIONS_FILE:
+
+    [
+        {"name": "uswds fixed header", "code": "Guideline1_4\\.1_4_10", "selector": "^html > body > (header|div:nth-child\\(\\d+\\))", "url": "^https://([a-z]+\\.)?example\\.gov", "expires": "2024-06-30", "justification": "The USWDS header and overlay are fixed on purpose and reflow at 320px, reviewed by the a11y team."}
+    ]
+
+code, selector and url are regular expressions matched against the issue code, selector and URL of an issue; a rule needs at least one of them and matches an issue if all that are given match. Every rule needs a justification. A rule with an expires date stops matching the day after it.
+
+Suppressed issues are not dropped: their issues rows are stored with suppressed set to true and the name and justification of the rule. The issue counts of the crawls rows (issues_total, issues_error, issues_warning and issues_notice) and the issues_total of the /scan and /crawl output are net of the suppressed issues, which are counted per page in issues_suppressed, so /scan/diff deltas and dashboards do not report accepted issues. access_score is A11yWatch's score over every issue of the page and is left as it is.
+
+Variables:
+    RuleConfig :
+        One entry of the suppressions file.
+    Rule :
+        A rule with its compiled patterns.
+    RULES :
+        The rules read at launch, shared by every scan.
+
+Functions:
+    load :
+        Reads and validates the suppressions file. Without SUPPRESSIONS_FILE there are no rules.
+    Rule::new :
+        Checks one entry of the suppressions file and compiles its patterns.
+    Rule::matches :
+        Whether the rule is active and matches an issues row.
+    apply, apply_rules :
+        Flags the matching issues rows, takes them out of the counts of the crawls rows and the output of every page and adds issues_suppressed to both.
+    subtract :
+        Lowers a count column of a row by the suppressed issues.
+
+Docker Vars:
+    SUPPRESSIONS_FILE :
+        Path to the JSON list of suppression rules.
+
+Output:
+    Every issues row gets "suppressed", "suppression_rule" and "suppression_justification", the last two null for issues that are not suppressed. Every crawls row and every page of the output gets "issues_suppressed".
+
+Errors:
+    failed to read suppressions file {}: {} / failed to parse suppressions file {}: {} :
+        The suppressions file could not be read or is not a list of rules. The service does not start.
+    suppression rule '{}' needs a justification :
+        A rule has no or an empty justification. The service does not start.
+    suppression rule '{}' needs a code, selector or url pattern :
+        A rule would match every issue. The service does not start.
+    invalid {} pattern for suppression rule '{}': {} :
+        A pattern is not a valid regular expression. The service does not start.
+*/
+
+#[derive(Deserialize)]
+struct RuleConfig {
+    name: String,
+    code: Option<String>,
+    selector: Option<String>,
+    url: Option<String>,
+    expires: Option<NaiveDate>,
+    #[serde(default)]
+    justification: String,
+}
+
+struct Rule {
+    name: String,
+    code: Option<Regex>,
+    selector: Option<Regex>,
+    url: Option<Regex>,
+    expires: Option<NaiveDate>,
+    justification: String,
+}
+
+static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+
+impl Rule {
+    // Check a rule of the suppressions file and compile its patterns
+    fn new(config: RuleConfig) -> Result<Self, String> {
+        if config.justification.trim().is_empty() {
+            return Err(format!(
+                "suppression rule '{}' needs a justification",
+                config.name
+            ));
+        }
+        if config.code.is_none() && config.selector.is_none() && config.url.is_none() {
+            return Err(format!(
+                "suppression rule '{}' needs a code, selector or url pattern",
+                config.name
+            ));
+        }
+        let compile = |kind: &str, pattern: &Option<String>| {
+            pattern.as_deref().map(Regex::new).transpose().map_err(|e| {
+                format!(
+                    "invalid {} pattern for suppression rule '{}': {}",
+                    kind, config.name, e
+                )
+            })
+        };
+        Ok(Rule {
+            code: compile("code", &config.code)?,
+            selector: compile("selector", &config.selector)?,
+            url: compile("url", &config.url)?,
+            name: config.name,
+            expires: config.expires,
+            justification: config.justification,
+        })
+    }
+
+    // Whether the rule has not expired and all its patterns match the row
+    fn matches(&self, row: &serde_json::Map<String, JsonValue>, today: NaiveDate) -> bool {
+        if self.expires.map_or(false, |expires| today > expires) {
+            return false;
+        }
+        let matches = |pattern: &Option<Regex>, column: &str| match pattern {
+            Some(pattern) => {
+                pattern.is_match(row.get(column).and_then(|v| v.as_str()).unwrap_or_default())
+            }
+            None => true,
+        };
+        matches(&self.code, "issue_code")
+            && matches(&self.selector, "selector")
+            && matches(&self.url, "url")
+    }
+}
+
+// Load the rules from SUPPRESSIONS_FILE, there are none if it is not set
+pub fn load() -> Result<(), String> {
+    let path = match get_env("SUPPRESSIONS_FILE") {
+        Ok(path) => path,
+        Err(_) => {
+            RULES.get_or_init(Vec::new);
+            return Ok(());
+        }
+    };
+    let file = std::fs::read_to_string(&path)
+        .map_err(|e| format!("failed to read suppressions file {}: {}", path, e))?;
+    let configs: Vec<RuleConfig> = serde_json::from_str(&file)
+        .map_err(|e| format!("failed to parse suppressions file {}: {}", path, e))?;
+
+    let today = Utc::now().date_naive();
+    let mut rules = Vec::new();
+    for config in configs {
+        let rule = Rule::new(config)?;
+        if rule.expires.map_or(false, |expires| today > expires) {
+            println!(
+                "suppress: rule '{}' expired on {}, it no longer suppresses issues",
+                rule.name,
+                rule.expires.unwrap()
+            );
+        }
+        rules.push(rule);
+    }
+    println!("suppress: loaded {} rule(s) from {}", rules.len(), path);
+    RULES.get_or_init(|| rules);
+    Ok(())
+}
+
+// The suppressed issues of a page, in total and by type
+#[derive(Default)]
+struct Suppressed {
+    total: i64,
+    error: i64,
+    warning: i64,
+    notice: i64,
+}
+
+// Lower a count column of a row by the suppressed issues, leaving a missing count as it is
+fn subtract(row: &mut serde_json::Map<String, JsonValue>, column: &str, count: i64) {
+    if let Some(value) = row.get(column).and_then(|v| v.as_i64()) {
+        row.insert(column.to_owned(), (value - count).max(0).into());
+    }
+}
+
+// Flag the suppressed issues rows and take them out of the counts of every page
+pub fn apply(issues: &mut JsonValue, crawls: &mut JsonValue, output: &mut JsonValue) {
+    let rules = RULES.get().map(Vec::as_slice).unwrap_or_default();
+    apply_rules(rules, Utc::now().date_naive(), issues, crawls, output);
+}
+
+// Apply the rules that are active today to the rows of some pages
+fn apply_rules(
+    rules: &[Rule],
+    today: NaiveDate,
+    issues: &mut JsonValue,
+    crawls: &mut JsonValue,
+    output: &mut JsonValue,
+) {
+    let mut suppressed: HashMap<String, Suppressed> = HashMap::new();
+    let rows = match issues {
+        JsonValue::Array(rows) => rows.iter_mut().collect(),
+        JsonValue::Object(_) => vec![issues],
+        _ => Vec::new(),
+    };
+    for row in rows {
+        let row = match row.as_object_mut() {
+            Some(row) => row,
+            None => continue,
+        };
+        let rule = rules.iter().find(|rule| rule.matches(row, today));
+        row.insert("suppressed".to_owned(), JsonValue::Bool(rule.is_some()));
+        row.insert(
+            "suppression_rule".to_owned(),
+            rule.map_or(JsonValue::Null, |rule| rule.name.clone().into()),
+        );
+        row.insert(
+            "suppression_justification".to_owned(),
+            rule.map_or(JsonValue::Null, |rule| rule.justification.clone().into()),
+        );
+        if rule.is_some() {
+            let url = row.get("url").and_then(|v| v.as_str()).unwrap_or_default();
+            let counts = suppressed.entry(url.to_owned()).or_default();
+            counts.total += 1;
+            match row.get("type").and_then(|v| v.as_str()) {
+                Some("error") => counts.error += 1,
+                Some("warning") => counts.warning += 1,
+                Some("notice") => counts.notice += 1,
+                _ => {}
+            }
+        }
+    }
+
+    // The crawls rows keep counts by type, the output only the total
+    let pages = [crawls, output].into_iter().flat_map(|rows| match rows {
+        JsonValue::Array(pages) => pages.iter_mut().collect(),
+        JsonValue::Object(_) => vec![rows],
+        _ => Vec::new(),
+    });
+    let none = Suppressed::default();
+    for page in pages {
+        if let Some(page) = page.as_object_mut() {
+            let url = page.get("url").and_then(|v| v.as_str()).unwrap_or_default();
+            let counts = suppressed.get(url).unwrap_or(&none);
+            subtract(page, "issues_total", counts.total);
+            subtract(page, "issues_error", counts.error);
+            subtract(page, "issues_warning", counts.warning);
+            subtract(page, "issues_notice", counts.notice);
+            page.insert("issues_suppressed".to_owned(), counts.total.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_rules, Rule, RuleConfig};
+    use chrono::NaiveDate;
+    use serde_json::{json, Value as JsonValue};
+
+    fn load_rule(config: JsonValue) -> Result<Rule, String> {
+        Rule::new(serde_json::from_value::<RuleConfig>(config).unwrap())
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    fn issue(url: &str, code: &str, selector: &str, issue_type: &str) -> JsonValue {
+        json!({"url": url, "issue_code": code, "selector": selector, "type": issue_type})
+    }
+
+    fn fixed_header() -> Rule {
+        load_rule(json!({
+            "name": "uswds fixed header",
+            "code": "Guideline1_4\\.1_4_10",
+            "selector": "^html > body > header",
+            "url": "^https://example\\.gov/",
+            "expires": "2024-06-30",
+            "justification": "Fixed on purpose, reflows at 320px."
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn every_given_pattern_must_match() {
+        let rule = fixed_header();
+        let today = date("2024-01-01");
+        let row = |url, code, selector| {
+            issue(url, code, selector, "warning")
+                .as_object()
+                .unwrap()
+                .clone()
+        };
+        let code = "WCAG2AA.Principle1.Guideline1_4.1_4_10.C32,C31,C33,C38,SCR34,G206";
+        assert!(rule.matches(
+            &row("https://example.gov/about", code, "html > body > header"),
+            today
+        ));
+        assert!(!rule.matches(
+            &row("https://other.gov/about", code, "html > body > header"),
+            today
+        ));
+        assert!(!rule.matches(
+            &row("https://example.gov/about", code, "html > body > main"),
+            today
+        ));
+        assert!(!rule.matches(
+            &row(
+                "https://example.gov/about",
+                "WCAG2AA.Principle1.Guideline1_1.1_1_1.H37",
+                "html > body > header"
+            ),
+            today
+        ));
+
+        // Patterns left out match every issue
+        let by_code =
+            load_rule(json!({"name": "alt", "code": "H37$", "justification": "Decorative."}))
+                .unwrap();
+        assert!(by_code.matches(&row("https://any.gov/", "WCAG2AA.H37", "img"), today));
+    }
+
+    #[test]
+    fn rule_stops_matching_the_day_after_it_expires() {
+        let rule = fixed_header();
+        let row = issue(
+            "https://example.gov/",
+            "Guideline1_4.1_4_10",
+            "html > body > header",
+            "warning",
+        );
+        let row = row.as_object().unwrap();
+        assert!(rule.matches(row, date("2024-06-30")));
+        assert!(!rule.matches(row, date("2024-07-01")));
+    }
+
+    #[test]
+    fn incomplete_rules_are_rejected() {
+        let error = load_rule(json!({"name": "everything", "justification": "Too noisy."}))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            "suppression rule 'everything' needs a code, selector or url pattern"
+        );
+        let error = load_rule(json!({"name": "unjustified", "code": "H37", "justification": " "}))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            "suppression rule 'unjustified' needs a justification"
+        );
+        assert!(load_rule(json!({"name": "bad", "selector": "(", "justification": "x"})).is_err());
+    }
+
+    #[test]
+    fn suppressed_issues_are_kept_but_not_counted() {
+        let url = "https://example.gov/";
+        let mut issues = json!([
+            issue(
+                url,
+                "Guideline1_4.1_4_10",
+                "html > body > header",
+                "warning"
+            ),
+            issue(url, "WCAG2AA.H37", "img", "error"),
+            issue(url, "WCAG2AA.H57", "html", "notice"),
+        ]);
+        let mut crawls = json!([{
+            "url": url, "issues_total": 3, "issues_error": 1, "issues_warning": 1, "issues_notice": 1
+        }]);
+        let mut output = json!({"url": url, "issues_total": 3});
+        apply_rules(
+            &[fixed_header()],
+            date("2024-01-01"),
+            &mut issues,
+            &mut crawls,
+            &mut output,
+        );
+
+        assert_eq!(issues.as_array().unwrap().len(), 3);
+        assert_eq!(issues[0]["suppressed"], true);
+        assert_eq!(issues[0]["suppression_rule"], "uswds fixed header");
+        assert_eq!(
+            issues[0]["suppression_justification"],
+            "Fixed on purpose, reflows at 320px."
+        );
+        assert_eq!(issues[1]["suppressed"], false);
+        assert_eq!(issues[1]["suppression_rule"], JsonValue::Null);
+
+        assert_eq!(crawls[0]["issues_total"], 2);
+        assert_eq!(crawls[0]["issues_error"], 1);
+        assert_eq!(crawls[0]["issues_warning"], 0);
+        assert_eq!(crawls[0]["issues_notice"], 1);
+        assert_eq!(crawls[0]["issues_suppressed"], 1);
+        assert_eq!(output["issues_total"], 2);
+        assert_eq!(output["issues_suppressed"], 1);
+    }
+}
@@ -3,23 +3,53 @@ extern crate rocket;
 extern crate serde_json;
 extern crate tokio;
 
-use crate::auth::ApiKey;
+use crate::auth::Caller;
 use crate::bigquery::read_up_targets;
 use crate::bigquery::store as bq_store;
+use crate::get_env_or;
+use crate::incident;
+use crate::jobs::{self, Job, JobRegistry};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::stream::{self, StreamExt};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509VerifyResult;
+use regex::Regex;
+use reqwest::{redirect::Policy, Client, StatusCode, Url};
 use rocket::post;
-use rocket::{http::Status, response::status, Data};
+use rocket::{http::Status, response::status, Data, State};
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::error::Error;
 use std::io::Read;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use trust_dns_resolver::proto::rr::RecordType;
+use trust_dns_resolver::TokioAsyncResolver;
 
 /*
 Code Summary:
-    This Rust code defines a single function catch_up to check the status of web pages. The function takes the input raw_data: Data and _key: ApiKey and returns a Result<String, rocket::response::status::Custom<std::string::String>> where the string is the parsed JSON data. This function parses the input JSON data to check the status of all targets in the list or the status of a specific target, using an HTTP client, and then stores this data in Google BigQuery.
+    This Rust code defines a single function catch_up to check the status of web pages. The function takes the input raw_data: Data and caller: Caller and returns a Result<String, rocket::response::status::Custom<std::string::String>> where the string is the parsed JSON data. This function parses the input JSON data to check the status of all targets in the list or the status of a specific target, using an HTTP client, and then stores this data in Google BigQuery.
+
+    When all targets are checked, the probes run concurrently, at most UP_CONCURRENCY at a time, and the results are written to BigQuery in one batch once every probe has finished. Each probe is given UP_TIMEOUT seconds in total, including its redirects. Both can be overridden per request with "concurrency" and "timeout" in the body.
+
+    A target is only reported as down once UP_CONFIRM of up to UP_ATTEMPTS probes have failed, so a single network blip does not count as an outage. Only probes that found the target down count as failures; a check that does not confirm an outage ends with the state of its last probe, "up" or "degraded". Probes are retried after UP_BACKOFF milliseconds, doubling before each further retry, and stop as soon as the outcome is certain. The number of probes made and the outcome of each are stored with the final status. These can be overridden per request with "attempts", "confirm" and "backoff" in the body.
+
+    A target may carry assertions, stored with its row of the up_targets table or given in the body next to a single "target": "expected_status" (a list of status codes), "body_contains", "body_regex", "body_forbidden" and "max_response_ms". A response that reaches the site (a 200-399 or expected status code) but fails an assertion is recorded with the state "degraded" instead of "up", so a 200 "under maintenance" page no longer counts as up. A degraded site is reachable, so it is not retried like a failure and does not open an incident. A body_regex that is not a valid regular expression is rejected with the request, or with the row of the up_targets table, as the target would never be up.
+
+    After the results are stored, they are compared with each target's previous state to open an incident when a target goes down and close it when the target is reachable again (see incident.rs).
+
+    Besides HTTP, a target can set "probe_type" to "tcp" (connect to "host:port"), "dns" (resolve the host's "record_type" A, AAAA or CNAME records, optionally expecting "expected_answer" among them) or "tls" (complete a handshake with "host[:port]", port 443 by default, and record when the certificate expires). Every probe type writes the same result, with its probe_type.
+
+    The certificate of TLS probes and of HTTP probes that end on an https URL is inspected: its issuer, subject alternative names, validity period and whether its chain and host name are valid are stored with the result. Certificates that expire within CERT_WARN_DAYS days are flagged in the cert_expiring column and listed in the response of a full cycle. The warning period can be overridden per request with "cert_warn_days".
+
+    Each HTTP probe follows redirects one hop at a time so the whole redirect chain is known. It records the HTTP status code, response time, redirect chain, final URL, response size, the class of error if the probe failed, and the time of the probe, so a slow site can be told apart from a site that is down or one with a broken redirect.
 
 Variables:
     raw_data :
         An input variable of type Data which is provided to the function catch_up.
-    _key :
-        An ApiKey variable which is not used in this function.
+    caller :
+        The API key, signed request or bearer token that made the request. A single target needs the "up:read" scope, a full cycle needs "up:cycle".
     buf :
         A mutable vector buffer to read the data into.
     data :
@@ -28,17 +58,76 @@ Variables:
         An instance of reqwest::Client.
     rt :
         An instance of tokio::runtime::Runtime.
+    UpTarget :
+        A URL to check and the assertions its response must pass. UpTarget::validate checks that its body_regex compiles.
+    ProbeSettings :
+        The timeout, number of attempts, number of failures that confirm an outage and the retry backoff of a check.
+    ProbeResult :
+        The outcome of one check, stored as one row of the ups table.
 
 Functions:
+    check :
+        Probes the target until UP_CONFIRM probes found it down or enough probes reached it that this cannot happen any more, and returns the deciding ProbeResult with every attempt recorded.
+    probe :
+        Runs the probe of the target's probe type within the timeout, checks max_response_ms and returns a ProbeResult. The certificate of an HTTP probe that ends on an https URL is inspected after the response time is taken, within a timeout of its own, so the extra handshake does not count toward the response time or the probe's timeout.
+    probe_http :
+        Sends GET requests to the target, following up to MAX_REDIRECTS redirects, and checks the final response.
+    probe_tcp :
+        Opens a TCP connection to the target's host and port.
+    probe_dns :
+        Looks up the target's records with the system's resolver and checks them against expected_answer.
+    probe_tls :
+        Completes a TLS handshake with the target, records its certificate and checks the certificate chain and host name.
+    inspect_certificate :
+        Completes a TLS handshake with certificate verification recorded but not enforced, and reads the details of the peer certificate.
+    record_certificate :
+        Copies the details of a certificate into the ProbeResult and flags it if it expires within the warning period.
+    failed_assertions :
+        Checks the status code and body of an HTTP response against the assertions of the target and describes each that failed.
+    follow :
+        Fetches the target for probe_http, recording the redirect chain as it goes, and reads the body.
+    fetch :
+        The request loop that follows redirects one hop at a time, also used by the security audit (see audit.rs).
+    host_port :
+        Splits a target into host and port.
+    io_error_class :
+        Sorts a failed TCP connection into "dns" or "connect".
+    asn1_to_utc :
+        Converts a certificate time to a chrono time.
+    read_settings :
+        Reads the concurrency and ProbeSettings from the body, falling back to the Docker vars below.
+    run_target :
+        Checks the single target of a request, stores the result and tracks its incident.
+    run_cycle :
+        Checks every target of the up_targets table, stores the results and tracks incidents. It is used when no target is given and by the scheduler (see schedule.rs). Run as a job, it reports its progress and records the targets found down or degraded, and the rows of up_targets that were rejected, as target errors.
+    error_class :
+        Sorts a failed request into "dns", "connect", "tls", "timeout" or "http".
     catch_up :
-        This function takes input raw_data: Data and _key: ApiKey and returns a Result<String, rocket::response::status::Custom<std::string::String>> where the string is the parsed JSON data. The function first creates an empty buffer to read the data into, then opens the raw data and reads it into the buffer. It then checks the status of all targets in the list or the status of a specific target using an HTTP client and stores this data in Google BigQuery.
+        This function takes input raw_data: Data and caller: Caller and returns a Result<String, rocket::response::status::Custom<std::string::String>> where the string is the parsed JSON data. The function first creates an empty buffer to read the data into, then opens the raw data and reads it into the buffer. It then checks the status of all targets in the list or the status of a specific target using an HTTP client and stores this data in Google BigQuery. With "async": true in the body, the check runs as a background job and the endpoint answers 202 Accepted with the job id (see jobs.rs).
 
 Docker Vars:
-    None
+    UP_CONCURRENCY :
+        Number of targets probed at the same time during a full cycle. Defaults to 16.
+    UP_TIMEOUT :
+        Number of seconds a probe may take, including redirects. Defaults to 30.
+    UP_ATTEMPTS :
+        Maximum number of probes per check. Defaults to 3.
+    UP_CONFIRM :
+        Number of failed probes that confirm a target is down. Defaults to UP_ATTEMPTS.
+    UP_BACKOFF :
+        Milliseconds to wait before the first retry, doubled before each further retry. Defaults to 1000.
+    CERT_WARN_DAYS :
+        Number of days before expiry at which a certificate is flagged. Defaults to 30.
 
 Output:
     The output of the catch_up function is a Result<String, rocket::response::status::Custom<std::string::String>> where the string is the parsed JSON data.
 
+    For a single target, the JSON data is the ProbeResult:
+        {"target", "probe_type", "status", "state", "status_code", "response_time_ms", "redirect_chain", "final_url", "response_size", "dns_answers", "cert_issuer", "cert_sans", "cert_not_before", "cert_expires_at", "cert_chain_valid", "cert_days_left", "cert_expiring", "error_class", "error", "probed_at", "attempts", "attempt_outcomes"}
+    The details are those of the last probe with the final outcome, probed_at is the time of the first probe. attempt_outcomes is a JSON encoded list with the status, state, status_code, error_class and response_time_ms of every probe. state is "up", "degraded" (the site was reached but an assertion failed) or "down", and status is true only if it is "up". redirect_chain is a JSON encoded list of the URLs that redirected. status_code, redirect_chain, final_url and response_size are only set by HTTP probes. dns_answers is a JSON encoded list of the answers of a DNS probe. The cert_ fields are set for TLS probes and HTTP probes of https URLs, cert_sans is a JSON encoded list and cert_expiring is true if cert_days_left is less than CERT_WARN_DAYS. error_class is one of "dns", "connect", "tls", "timeout", "http" (a 400-599 response that is not expected), "redirect" (too many redirects) "assertion" (the error lists the failed assertions) or "config" (an unknown probe_type or record_type).
+
+    For all targets, the output is "scanned {} target(s), opened {} and closed {} incident(s)", followed by ", {} certificate(s) expire within {} days: {}" and the targets if any certificates are flagged, and by ", skipped {} invalid target(s): {}" and the reasons if rows of up_targets were rejected.
+
 Errors:
     failed to read body data: {} :
         Indicates that the function failed to read the input data.
@@ -49,24 +138,40 @@ Errors:
     target must be a string :
         Indicates that the input target is not of the expected type string.
 
+    invalid assertions: {} :
+        Indicates that the assertions given with a single target are not of the expected types.
+
+    invalid target: {} :
+        Indicates that the body_regex of a single target is not a valid regular expression, or that a tcp target has no port.
+
+    {} must be an integer from {} to {} :
+        Indicates that concurrency (1 to 256), timeout (1 to 300), attempts or confirm (1 to 10), backoff (0 to 60000) or cert_warn_days (1 to 365) in the input or the Docker vars is not an integer within its range.
+
+    confirm must not be greater than attempts :
+        Indicates that more failures are needed to confirm an outage than probes are made.
+
     failed to store data to google big query: {} :
         Indicates that the function failed to store the data in Google BigQuery.
 
     failed to read target list from google big query: {} :
         Indicates that the function failed to read the target list from Google BigQuery.
 
+    failed to track incidents: {} :
+        Indicates that the incidents of the checked targets could not be read or updated in Google BigQuery.
+
 
 
 */
 
 // This function takes a Rocket Data object as input
-// It also takes an ApiKey that is currently unused
+// It also takes the Caller that made the request, which must hold the scope for the requested action
 // It returns a Result<String, Custom<String>> where the string is the parsed JSON data
 #[post("/up", data = "<raw_data>")]
 pub(crate) fn catch_up(
     raw_data: Data,
-    _key: ApiKey,
-) -> Result<String, rocket::response::status::Custom<std::string::String>> {
+    registry: State<JobRegistry>,
+    caller: Caller,
+) -> Result<status::Custom<String>, rocket::response::status::Custom<std::string::String>> {
     // Create an empty buffer to read the data into
     let mut buf = Vec::new();
 
@@ -83,6 +188,9 @@ pub(crate) fn catch_up(
             )
         })?;
 
+    // Check the signature of signed requests against the body
+    caller.verify_body(&buf)?;
+
     // If the buffer is empty, return an empty Map object
     // Otherwise, parse the buffer as JSON
     let data: serde_json::Map<String, JsonValue> = if buf.is_empty() {
@@ -96,73 +204,904 @@ pub(crate) fn catch_up(
         })?
     };
 
-    // Create a new client to make HTTP requests
-    let client = reqwest::Client::new();
+    // Read the probe settings, the body overrides the environment
+    let (concurrency, settings) = read_settings(&data)?;
+
+    // With "async": true the check runs as a background job (see jobs.rs)
+    let run_async = jobs::wants_async(&data);
 
-    // Create a new runtime to run async tasks
-    let rt = tokio::runtime::Runtime::new().unwrap();
     if let Some(target) = data.get("target") {
         // If a specific target is given, check its status
-        let target = target.as_str().ok_or_else(|| {
-            status::Custom(Status::BadRequest, "target must be a string".to_owned())
-        })?;
-        let status = match rt.block_on(client.get(target).send()) {
-            Ok(response)
-                if response.status().as_u16() >= 200 && response.status().as_u16() <= 399 =>
-            {
-                true
-            }
-            _ => false,
-        };
+        caller.require("up:read")?;
+        if !target.is_string() {
+            return Err(status::Custom(
+                Status::BadRequest,
+                "target must be a string".to_owned(),
+            ));
+        }
 
-        // Store target and status in BigQuery
-        let data = serde_json::json!({"target": target, "status": status});
-        rt.block_on(bq_store("rusty_a11y".to_owned(), "ups".to_owned(), &data))
-            .map_err(|e| {
-                status::Custom(
-                    Status::InternalServerError,
-                    format!("failed to store data to google big query: {}", e),
-                )
+        // The assertions of a single target are read from the body
+        let target: UpTarget =
+            serde_json::from_value(JsonValue::Object(data.clone())).map_err(|e| {
+                status::Custom(Status::BadRequest, format!("invalid assertions: {}", e))
+            })?;
+        target
+            .validate()
+            .map_err(|e| status::Custom(Status::BadRequest, format!("invalid target: {}", e)))?;
+        if run_async {
+            let job = registry.spawn(&caller, "up", Some(target.url.clone()), 1, move |_| {
+                run_target(&target, &settings)
             })?;
-        Ok(data.to_string())
+            return Ok(status::Custom(Status::Accepted, jobs::accepted(&job)));
+        }
+        Ok(status::Custom(
+            Status::Ok,
+            run_target(&target, &settings)?.to_string(),
+        ))
     } else {
         // If no specific target is given, check the status of all targets in the list
-        let targets = rt
-            .block_on(read_up_targets("rusty_a11y".to_owned()))
-            .map_err(|e| {
-                status::Custom(
-                    Status::InternalServerError,
-                    format!("failed to read target list from google big query: {}", e),
-                )
+        caller.require("up:cycle")?;
+        if run_async {
+            let job = registry.spawn(&caller, "up", None, 0, move |job| {
+                run_cycle(concurrency, &settings, Some(job)).map(JsonValue::String)
             })?;
-        let mut bq_json = Vec::new();
-        for target in targets.into_iter() {
-            // Check the status of each target
-            let status = match rt.block_on(client.get(target.clone()).send()) {
-                Ok(response)
-                    if response.status().as_u16() >= 200 && response.status().as_u16() <= 399 =>
-                {
-                    true
-                }
-                _ => false,
-            };
-            let data = serde_json::json!({"target": target, "status": status});
-            bq_json.push(data);
-        }
-        let msg = format!("scanned {} target(s)", bq_json.len());
-
-        // Store target and status list in BigQuery
-        rt.block_on(bq_store(
-            "rusty_a11y".to_owned(),
-            "ups".to_owned(),
-            &serde_json::Value::Array(bq_json),
+            return Ok(status::Custom(Status::Accepted, jobs::accepted(&job)));
+        }
+        Ok(status::Custom(
+            Status::Ok,
+            run_cycle(concurrency, &settings, None)?,
         ))
+    }
+}
+
+// Check a single target, store the result and track its incident
+pub fn run_target(
+    target: &UpTarget,
+    settings: &ProbeSettings,
+) -> Result<JsonValue, rocket::response::status::Custom<std::string::String>> {
+    // Create a new client to make HTTP requests, redirects are followed by probe
+    let client = Client::builder().redirect(Policy::none()).build().unwrap();
+
+    // Create a new runtime to run async tasks
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let result = rt.block_on(check(&client, target, settings));
+
+    // Store the probe result in BigQuery
+    let data = serde_json::to_value(&result).unwrap();
+    rt.block_on(bq_store("rusty_a11y".to_owned(), "ups".to_owned(), &data))
         .map_err(|e| {
             status::Custom(
                 Status::InternalServerError,
                 format!("failed to store data to google big query: {}", e),
             )
         })?;
-        Ok(msg)
+
+    // Open or close the target's incident if its state changed
+    rt.block_on(incident::track(&[result])).map_err(|e| {
+        status::Custom(
+            Status::InternalServerError,
+            format!("failed to track incidents: {}", e),
+        )
+    })?;
+    Ok(data)
+}
+
+// Largest values accepted for the settings of a check
+const MAX_CONCURRENCY: u64 = 256;
+const MAX_TIMEOUT: u64 = 300;
+const MAX_ATTEMPTS: u64 = 10;
+const MAX_BACKOFF: u64 = 60_000;
+const MAX_CERT_WARN_DAYS: u64 = 365;
+
+// Read the concurrency and probe settings from a request body, falling back to the environment
+pub fn read_settings(
+    data: &serde_json::Map<String, JsonValue>,
+) -> Result<(usize, ProbeSettings), rocket::response::status::Custom<std::string::String>> {
+    // A setting of the body or the environment, between min and max
+    let setting = |name: &str, default: u64, min: u64, max: u64| {
+        let value = match data.get(name) {
+            Some(value) => value.as_u64(),
+            None => Some(default),
+        };
+        value.filter(|v| (min..=max).contains(v)).ok_or_else(|| {
+            status::Custom(
+                Status::BadRequest,
+                format!("{} must be an integer from {} to {}", name, min, max),
+            )
+        })
+    };
+    // try_from cannot fail below the maximums, but keeps a wrong maximum from truncating
+    let to_u32 = |value: u64| {
+        u32::try_from(value).map_err(|_| {
+            status::Custom(
+                Status::BadRequest,
+                "attempts and confirm must fit in 32 bits".to_owned(),
+            )
+        })
+    };
+    let concurrency = setting(
+        "concurrency",
+        get_env_or("UP_CONCURRENCY", 16),
+        1,
+        MAX_CONCURRENCY,
+    )? as usize;
+    let attempts = setting("attempts", get_env_or("UP_ATTEMPTS", 3), 1, MAX_ATTEMPTS)?;
+    let settings = ProbeSettings {
+        timeout: Duration::from_secs(setting(
+            "timeout",
+            get_env_or("UP_TIMEOUT", 30),
+            1,
+            MAX_TIMEOUT,
+        )?),
+        attempts: to_u32(attempts)?,
+        confirm: to_u32(setting(
+            "confirm",
+            get_env_or("UP_CONFIRM", attempts),
+            1,
+            MAX_ATTEMPTS,
+        )?)?,
+        backoff: Duration::from_millis(setting(
+            "backoff",
+            get_env_or("UP_BACKOFF", 1000),
+            0,
+            MAX_BACKOFF,
+        )?),
+        cert_warn_days: setting(
+            "cert_warn_days",
+            get_env_or("CERT_WARN_DAYS", 30),
+            1,
+            MAX_CERT_WARN_DAYS,
+        )?,
+    };
+    if settings.confirm > settings.attempts {
+        return Err(status::Custom(
+            Status::BadRequest,
+            "confirm must not be greater than attempts".to_owned(),
+        ));
+    }
+    Ok((concurrency, settings))
+}
+
+// Check the status of all targets in the up_targets table and track their incidents
+pub fn run_cycle(
+    concurrency: usize,
+    settings: &ProbeSettings,
+    job: Option<&Job>,
+) -> Result<String, rocket::response::status::Custom<std::string::String>> {
+    // Create a new client to make HTTP requests, redirects are followed by probe
+    let client = Client::builder().redirect(Policy::none()).build().unwrap();
+
+    // Create a new runtime to run async tasks
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (targets, invalid) = rt
+        .block_on(read_up_targets("rusty_a11y".to_owned()))
+        .map_err(|e| {
+            status::Custom(
+                Status::InternalServerError,
+                format!("failed to read target list from google big query: {}", e),
+            )
+        })?;
+    // Check the status of the targets, at most `concurrency` at a time
+    if let Some(job) = job {
+        job.set_total(targets.len());
+        for (target, error) in &invalid {
+            job.target_error(target, error);
+        }
+    }
+    let results: Vec<ProbeResult> = rt.block_on(
+        stream::iter(targets.iter())
+            .map(|target| async {
+                let result = check(&client, target, settings).await;
+                // As a job, report the progress and the targets that are down
+                if let Some(job) = job {
+                    if !result.status {
+                        let error = result.error.as_deref().unwrap_or(&result.state);
+                        job.target_error(&result.target, error);
+                    }
+                    job.advance();
+                }
+                result
+            })
+            .buffer_unordered(concurrency)
+            .collect(),
+    );
+    let bq_json: Vec<JsonValue> = results
+        .iter()
+        .map(|result| serde_json::to_value(result).unwrap())
+        .collect();
+
+    // Store target and status list in BigQuery
+    rt.block_on(bq_store(
+        "rusty_a11y".to_owned(),
+        "ups".to_owned(),
+        &serde_json::Value::Array(bq_json),
+    ))
+    .map_err(|e| {
+        status::Custom(
+            Status::InternalServerError,
+            format!("failed to store data to google big query: {}", e),
+        )
+    })?;
+
+    // Open incidents for targets that went down, close those that can be reached again
+    let (opened, closed) = rt.block_on(incident::track(&results)).map_err(|e| {
+        status::Custom(
+            Status::InternalServerError,
+            format!("failed to track incidents: {}", e),
+        )
+    })?;
+
+    // Flag the targets whose certificates expire soon
+    let expiring: Vec<&str> = results
+        .iter()
+        .filter(|result| result.cert_expiring == Some(true))
+        .map(|result| result.target.as_str())
+        .collect();
+    let mut msg = format!(
+        "scanned {} target(s), opened {} and closed {} incident(s)",
+        results.len(),
+        opened,
+        closed
+    );
+    if !expiring.is_empty() {
+        msg.push_str(&format!(
+            ", {} certificate(s) expire within {} days: {}",
+            expiring.len(),
+            settings.cert_warn_days,
+            expiring.join(", ")
+        ));
+    }
+    if !invalid.is_empty() {
+        let reasons: Vec<String> = invalid
+            .iter()
+            .map(|(target, error)| format!("{} ({})", target, error))
+            .collect();
+        msg.push_str(&format!(
+            ", skipped {} invalid target(s): {}",
+            invalid.len(),
+            reasons.join(", ")
+        ));
+    }
+    Ok(msg)
+}
+
+// Maximum number of redirects a probe follows
+const MAX_REDIRECTS: usize = 10;
+
+// A URL to check and the assertions its response must pass, one row of the up_targets table
+#[derive(Deserialize, Debug, Default)]
+pub struct UpTarget {
+    #[serde(rename = "target")]
+    pub url: String,
+    #[serde(default)]
+    pub expected_status: Vec<u16>,
+    pub body_contains: Option<String>,
+    pub body_regex: Option<String>,
+    pub body_forbidden: Option<String>,
+    pub max_response_ms: Option<u64>,
+    // "http" (the default), "tcp", "dns" or "tls"
+    pub probe_type: Option<String>,
+    // DNS only: "A" (the default), "AAAA" or "CNAME", and an answer the lookup must return
+    pub record_type: Option<String>,
+    pub expected_answer: Option<String>,
+}
+
+impl UpTarget {
+    pub fn probe_type(&self) -> &str {
+        self.probe_type.as_deref().unwrap_or("http")
+    }
+
+    // Reject assertions the target could never pass, and tcp targets that do not say which port to probe
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(pattern) = &self.body_regex {
+            Regex::new(pattern).map_err(|e| format!("invalid body_regex: {}", e))?;
+        }
+        if self.probe_type() == "tcp" && split_host_port(&self.url).1.is_none() {
+            return Err(format!(
+                "tcp target {} has no port, it must be host:port",
+                self.url
+            ));
+        }
+        Ok(())
+    }
+}
+
+// The outcome of one probe, one row of the ups table
+#[derive(Serialize, Debug)]
+pub struct ProbeResult {
+    pub target: String,
+    pub probe_type: String,
+    pub status: bool,
+    pub state: String,
+    pub status_code: Option<u16>,
+    pub response_time_ms: u64,
+    pub redirect_chain: String,
+    pub final_url: Option<String>,
+    pub response_size: Option<u64>,
+    pub dns_answers: Option<String>,
+    pub cert_issuer: Option<String>,
+    pub cert_sans: Option<String>,
+    pub cert_not_before: Option<String>,
+    pub cert_expires_at: Option<String>,
+    pub cert_chain_valid: Option<bool>,
+    pub cert_days_left: Option<i64>,
+    pub cert_expiring: Option<bool>,
+    pub error_class: Option<String>,
+    pub error: Option<String>,
+    pub probed_at: String,
+    pub attempts: u32,
+    pub attempt_outcomes: String,
+}
+
+pub struct ProbeSettings {
+    pub timeout: Duration,
+    pub attempts: u32,
+    pub confirm: u32,
+    pub backoff: Duration,
+    pub cert_warn_days: u64,
+}
+
+// Probe a target until the N-of-M rule decides whether it is down, or up or degraded
+pub async fn check(client: &Client, target: &UpTarget, settings: &ProbeSettings) -> ProbeResult {
+    let mut outcomes: Vec<JsonValue> = Vec::new();
+    let mut probed_at = None;
+    let mut failures = 0;
+    let mut successes = 0;
+    let mut backoff = settings.backoff;
+
+    // The last probe has the deciding outcome, its details are the ones returned
+    let mut result = loop {
+        let result = probe(client, target, settings).await;
+        probed_at.get_or_insert_with(|| result.probed_at.clone());
+        outcomes.push(serde_json::json!({
+            "status": result.status,
+            "state": result.state,
+            "status_code": result.status_code,
+            "error_class": result.error_class,
+            "response_time_ms": result.response_time_ms,
+        }));
+        // A degraded site was reached, only probes that found it down are failures
+        if result.state == "down" {
+            failures += 1;
+        } else {
+            successes += 1;
+        }
+
+        // Down once `confirm` probes failed, up or degraded once that can no longer happen
+        if failures >= settings.confirm
+            || successes > settings.attempts.saturating_sub(settings.confirm)
+        {
+            break result;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = backoff.saturating_mul(2);
+    };
+    if let Some(probed_at) = probed_at {
+        result.probed_at = probed_at;
+    }
+    result.attempts = outcomes.len() as u32;
+    result.attempt_outcomes = JsonValue::Array(outcomes).to_string();
+    result
+}
+
+// Sort a failed request into the class of its error
+fn error_class(e: &reqwest::Error) -> &'static str {
+    if e.is_timeout() {
+        return "timeout";
+    }
+
+    // The lower level errors are only available as messages in the source chain
+    let mut messages = e.to_string();
+    let mut source = e.source();
+    while let Some(s) = source {
+        messages.push_str(&s.to_string());
+        source = s.source();
+    }
+    let messages = messages.to_lowercase();
+    if messages.contains("dns error") || messages.contains("failed to lookup address") {
+        "dns"
+    } else if messages.contains("certificate")
+        || messages.contains("ssl")
+        || messages.contains("tls")
+    {
+        "tls"
+    } else if e.is_connect() {
+        "connect"
+    } else {
+        "http"
+    }
+}
+
+// Check an HTTP response against the assertions of the target, and describe every failed one
+fn failed_assertions(target: &UpTarget, status: u16, body: &str) -> Vec<String> {
+    let mut failures = Vec::new();
+    if !target.expected_status.is_empty() && !target.expected_status.contains(&status) {
+        failures.push(format!(
+            "status {} is not one of {:?}",
+            status, target.expected_status
+        ));
+    }
+    if let Some(text) = &target.body_contains {
+        if !body.contains(text.as_str()) {
+            failures.push(format!("body does not contain {:?}", text));
+        }
+    }
+    if let Some(pattern) = &target.body_regex {
+        match Regex::new(pattern) {
+            Ok(regex) if regex.is_match(body) => {}
+            Ok(_) => failures.push(format!("body does not match /{}/", pattern)),
+            Err(e) => failures.push(format!("invalid body_regex: {}", e)),
+        }
+    }
+    if let Some(text) = &target.body_forbidden {
+        if body.contains(text.as_str()) {
+            failures.push(format!("body contains {:?}", text));
+        }
+    }
+    failures
+}
+
+// Probe a target with its probe type and decide whether it is up, degraded or down
+pub async fn probe(client: &Client, target: &UpTarget, settings: &ProbeSettings) -> ProbeResult {
+    let timeout = settings.timeout;
+    let start = Instant::now();
+    let mut result = ProbeResult {
+        target: target.url.clone(),
+        probe_type: target.probe_type().to_owned(),
+        status: false,
+        state: "down".to_owned(),
+        status_code: None,
+        response_time_ms: 0,
+        redirect_chain: "[]".to_owned(),
+        final_url: None,
+        response_size: None,
+        dns_answers: None,
+        cert_issuer: None,
+        cert_sans: None,
+        cert_not_before: None,
+        cert_expires_at: None,
+        cert_chain_valid: None,
+        cert_days_left: None,
+        cert_expiring: None,
+        error_class: None,
+        error: None,
+        probed_at: Utc::now().format("%Y-%m-%d %H:%M:%S%.3f UTC").to_string(),
+        attempts: 1,
+        attempt_outcomes: String::new(),
+    };
+
+    // Give up on the whole probe, redirects included, once the timeout has passed
+    let outcome = tokio::time::timeout(timeout, async {
+        match target.probe_type() {
+            "http" => probe_http(client, target, &mut result).await,
+            "tcp" => probe_tcp(target).await,
+            "dns" => probe_dns(target, &mut result).await,
+            "tls" => probe_tls(target, settings, &mut result).await,
+            other => Err(("config", format!("unknown probe_type '{}'", other))),
+        }
+    })
+    .await
+    .unwrap_or_else(|_| {
+        Err((
+            "timeout",
+            format!("no response within {} seconds", timeout.as_secs()),
+        ))
+    });
+    result.response_time_ms = start.elapsed().as_millis() as u64;
+
+    // Read the certificate of an HTTPS site with a separate handshake, reqwest does not expose it.
+    // This runs after the response time is taken, with a timeout of its own
+    let final_url = result.final_url.clone().unwrap_or_default();
+    if result.status_code.is_some() && final_url.starts_with("https://") {
+        let (host, port) = host_port(&final_url, 443);
+        if let Ok(Ok(certificate)) =
+            tokio::time::timeout(timeout, inspect_certificate(&host, port, timeout)).await
+        {
+            record_certificate(&mut result, &certificate, settings.cert_warn_days);
+        }
+    }
+
+    // The response time is an assertion of every probe type
+    let (state, error_class, error) = match outcome {
+        Err((class, error)) => ("down", Some(class.to_owned()), Some(error)),
+        Ok(mut failures) => {
+            if let Some(max) = target.max_response_ms {
+                if result.response_time_ms > max {
+                    failures.push(format!(
+                        "response took {} ms, more than {} ms",
+                        result.response_time_ms, max
+                    ));
+                }
+            }
+            if failures.is_empty() {
+                ("up", None, None)
+            } else {
+                (
+                    "degraded",
+                    Some("assertion".to_owned()),
+                    Some(failures.join("; ")),
+                )
+            }
+        }
+    };
+    result.status = state == "up";
+    result.state = state.to_owned();
+    result.error_class = error_class;
+    result.error = error;
+    result
+}
+
+// Split a target into host and port, using the default port if the target has none
+fn host_port(target: &str, default_port: u16) -> (String, u16) {
+    let (host, port) = split_host_port(target);
+    (host, port.unwrap_or(default_port))
+}
+
+// Split a target into host and port, it may be "host", "host:port", "[ipv6]", "[ipv6]:port",
+// a bare IPv6 address or a URL, whose port may follow from its scheme
+fn split_host_port(target: &str) -> (String, Option<u16>) {
+    if let Ok(url) = Url::parse(target) {
+        if let Some(host) = url.host_str() {
+            return (
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_owned(),
+                url.port_or_known_default(),
+            );
+        }
+    }
+    // A bracketed IPv6 address, with or without a port
+    if let Some((host, rest)) = target
+        .strip_prefix('[')
+        .and_then(|target| target.split_once(']'))
+    {
+        let port = rest.strip_prefix(':').and_then(|port| port.parse().ok());
+        return (host.to_owned(), port);
+    }
+    // More than one colon is a bare IPv6 address, which has no port
+    if target.matches(':').count() > 1 {
+        return (target.to_owned(), None);
+    }
+    match target.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => (host.to_owned(), port.parse().ok()),
+        _ => (target.to_owned(), None),
+    }
+}
+
+// Sort a failed connection into the class of its error
+fn io_error_class(e: &std::io::Error) -> &'static str {
+    if e.to_string().contains("failed to lookup address") {
+        "dns"
+    } else {
+        "connect"
+    }
+}
+
+// Send GET requests, following redirects, and check the final response
+async fn probe_http(
+    client: &Client,
+    target: &UpTarget,
+    result: &mut ProbeResult,
+) -> Result<Vec<String>, (&'static str, String)> {
+    let (status, body) = follow(client, &target.url, result).await?;
+    let code = status.as_u16();
+    result.status_code = Some(code);
+    result.response_size = Some(body.len() as u64);
+
+    // A 200-399 or expected status code reached the site, the assertions decide if it is up
+    if !((200..=399).contains(&code) || target.expected_status.contains(&code)) {
+        return Err(("http", status.to_string()));
+    }
+    Ok(failed_assertions(
+        target,
+        code,
+        &String::from_utf8_lossy(&body),
+    ))
+}
+
+// Open a TCP connection to the target's port
+async fn probe_tcp(target: &UpTarget) -> Result<Vec<String>, (&'static str, String)> {
+    // validate rejects tcp targets without a port, no port is guessed
+    let (host, port) = match split_host_port(&target.url) {
+        (host, Some(port)) => (host, port),
+        (_, None) => return Err(("config", format!("tcp target {} has no port", target.url))),
+    };
+    TcpStream::connect((host.as_str(), port))
+        .await
+        .map_err(|e| (io_error_class(&e), e.to_string()))?;
+    Ok(Vec::new())
+}
+
+// Resolve the target's host name and check the answers against the expected answer
+async fn probe_dns(
+    target: &UpTarget,
+    result: &mut ProbeResult,
+) -> Result<Vec<String>, (&'static str, String)> {
+    let (host, _) = host_port(&target.url, 0);
+    let record_type = match target
+        .record_type
+        .as_deref()
+        .unwrap_or("A")
+        .to_uppercase()
+        .as_str()
+    {
+        "A" => RecordType::A,
+        "AAAA" => RecordType::AAAA,
+        "CNAME" => RecordType::CNAME,
+        other => return Err(("config", format!("unknown record_type '{}'", other))),
+    };
+    let resolver =
+        TokioAsyncResolver::tokio_from_system_conf().map_err(|e| ("dns", e.to_string()))?;
+    let lookup = resolver
+        .lookup(host.as_str(), record_type)
+        .await
+        .map_err(|e| ("dns", e.to_string()))?;
+
+    // Names are compared without the trailing dot and case
+    let answers: Vec<String> = lookup
+        .iter()
+        .map(|answer| answer.to_string().trim_end_matches('.').to_lowercase())
+        .collect();
+    result.dns_answers = Some(serde_json::to_string(&answers).unwrap());
+
+    let mut failures = Vec::new();
+    if let Some(expected) = &target.expected_answer {
+        let expected = expected.trim_end_matches('.').to_lowercase();
+        if !answers.contains(&expected) {
+            failures.push(format!("answers do not include {:?}", expected));
+        }
+    }
+    Ok(failures)
+}
+
+// The details of a peer certificate
+struct Certificate {
+    issuer: String,
+    sans: Vec<String>,
+    not_before: Option<DateTime<Utc>>,
+    not_after: Option<DateTime<Utc>>,
+    // Why the chain or host name is not valid, None if they are
+    invalid: Option<String>,
+}
+
+// Complete a TLS handshake with a host and read its certificate, even if it is not valid
+async fn inspect_certificate(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<Certificate, (&'static str, String)> {
+    let stream = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| (io_error_class(&e), e.to_string()))?
+        .into_std()
+        .map_err(|e| ("connect", e.to_string()))?;
+
+    // openssl blocks, so the handshake runs on a blocking thread bounded by socket timeouts
+    let host = host.to_owned();
+    tokio::task::spawn_blocking(move || {
+        stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(timeout)))
+            .and_then(|_| stream.set_write_timeout(Some(timeout)))
+            .map_err(|e| ("connect", e.to_string()))?;
+
+        // Verify the chain without failing the handshake, so an invalid certificate is still seen
+        let mut connector =
+            SslConnector::builder(SslMethod::tls()).map_err(|e| ("tls", e.to_string()))?;
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = connector
+            .build()
+            .connect(&host, stream)
+            .map_err(|e| ("tls", e.to_string()))?;
+        let certificate = stream
+            .ssl()
+            .peer_certificate()
+            .ok_or(("tls", "no peer certificate".to_owned()))?;
+
+        // The issuer as "CN=..., O=...", and the DNS names and IP addresses it is valid for
+        let issuer = certificate
+            .issuer_name()
+            .entries()
+            .filter_map(|entry| {
+                Some(format!(
+                    "{}={}",
+                    entry.object().nid().short_name().ok()?,
+                    entry.data().as_utf8().ok()?
+                ))
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sans = certificate
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        name.dnsname().map(|dns| dns.to_owned()).or_else(|| {
+                            match name.ipaddress()? {
+                                ip if ip.len() == 4 => Some(
+                                    std::net::Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?)
+                                        .to_string(),
+                                ),
+                                ip => Some(
+                                    std::net::Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?)
+                                        .to_string(),
+                                ),
+                            }
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let verify = stream.ssl().verify_result();
+        Ok(Certificate {
+            issuer,
+            sans,
+            not_before: asn1_to_utc(certificate.not_before()),
+            not_after: asn1_to_utc(certificate.not_after()),
+            invalid: if verify == X509VerifyResult::OK {
+                None
+            } else {
+                Some(verify.error_string().to_owned())
+            },
+        })
+    })
+    .await
+    .map_err(|e| ("tls", e.to_string()))?
+}
+
+// Record the details of a certificate and flag it if it expires within the warning period
+fn record_certificate(result: &mut ProbeResult, certificate: &Certificate, warn_days: u64) {
+    let format = |time: DateTime<Utc>| time.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+    result.cert_issuer = Some(certificate.issuer.clone());
+    result.cert_sans = Some(serde_json::to_string(&certificate.sans).unwrap());
+    result.cert_not_before = certificate.not_before.map(format);
+    result.cert_expires_at = certificate.not_after.map(format);
+    result.cert_chain_valid = Some(certificate.invalid.is_none());
+    if let Some(not_after) = certificate.not_after {
+        let days_left = (not_after - Utc::now()).num_days();
+        result.cert_days_left = Some(days_left);
+        result.cert_expiring = Some(days_left < warn_days as i64);
+    }
+}
+
+// Complete a TLS handshake with the target and check its certificate
+async fn probe_tls(
+    target: &UpTarget,
+    settings: &ProbeSettings,
+    result: &mut ProbeResult,
+) -> Result<Vec<String>, (&'static str, String)> {
+    let (host, port) = host_port(&target.url, 443);
+    let certificate = inspect_certificate(&host, port, settings.timeout).await?;
+    record_certificate(result, &certificate, settings.cert_warn_days);
+    if let Some(reason) = certificate.invalid {
+        return Err(("tls", format!("certificate is not valid: {}", reason)));
+    }
+    Ok(Vec::new())
+}
+
+// Convert a certificate time to a UTC date and time
+fn asn1_to_utc(time: &Asn1TimeRef) -> Option<DateTime<Utc>> {
+    let diff = Asn1Time::from_unix(0).ok()?.diff(time).ok()?;
+    Utc.timestamp_opt(diff.days as i64 * 86400 + diff.secs as i64, 0)
+        .single()
+}
+
+// Send GET requests until a response is not a redirect, and return its status code and body
+async fn follow(
+    client: &Client,
+    target: &str,
+    result: &mut ProbeResult,
+) -> Result<(StatusCode, Vec<u8>), (&'static str, String)> {
+    result.final_url = Some(target.to_owned());
+    let response = fetch(client, target, |chain, url| {
+        result.redirect_chain = serde_json::to_string(chain).unwrap();
+        result.final_url = Some(url.to_owned());
+    })
+    .await?;
+    let status = response.status();
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| (error_class(&e), e.to_string()))?;
+    Ok((status, body.to_vec()))
+}
+
+// Send GET requests, following up to MAX_REDIRECTS redirects, and return the final response
+// on_redirect is called with the redirect chain and the next URL after every redirect
+pub async fn fetch(
+    client: &Client,
+    target: &str,
+    mut on_redirect: impl FnMut(&[String], &str),
+) -> Result<reqwest::Response, (&'static str, String)> {
+    let mut chain: Vec<String> = Vec::new();
+    let mut url = target.to_owned();
+    loop {
+        let response = client
+            .get(url.as_str())
+            .send()
+            .await
+            .map_err(|e| (error_class(&e), e.to_string()))?;
+
+        // Follow the Location header of a redirect, relative to the current URL
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get("Location")
+                .and_then(|l| l.to_str().ok())
+                .and_then(|l| Url::parse(&url).and_then(|base| base.join(l)).ok());
+            if let Some(next) = location {
+                if chain.len() >= MAX_REDIRECTS {
+                    return Err(("redirect", format!("more than {} redirects", MAX_REDIRECTS)));
+                }
+                chain.push(std::mem::replace(&mut url, next.to_string()));
+                on_redirect(&chain, &url);
+                continue;
+            }
+        }
+        return Ok(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{host_port, UpTarget};
+
+    #[test]
+    fn host_port_splits_host_names() {
+        assert_eq!(
+            host_port("mail.example.gov:25", 443),
+            ("mail.example.gov".to_owned(), 25)
+        );
+        assert_eq!(
+            host_port("example.gov", 443),
+            ("example.gov".to_owned(), 443)
+        );
+        assert_eq!(
+            host_port("https://example.gov:8443/path", 443),
+            ("example.gov".to_owned(), 8443)
+        );
+    }
+
+    #[test]
+    fn host_port_handles_ipv6() {
+        assert_eq!(
+            host_port("2001:db8::1", 443),
+            ("2001:db8::1".to_owned(), 443)
+        );
+        assert_eq!(
+            host_port("[2001:db8::1]:25", 443),
+            ("2001:db8::1".to_owned(), 25)
+        );
+        assert_eq!(
+            host_port("[2001:db8::1]", 443),
+            ("2001:db8::1".to_owned(), 443)
+        );
+        assert_eq!(
+            host_port("https://[2001:db8::1]:8443/", 443),
+            ("2001:db8::1".to_owned(), 8443)
+        );
+    }
+
+    #[test]
+    fn tcp_targets_need_a_port() {
+        let tcp = |url: &str| UpTarget {
+            url: url.to_owned(),
+            probe_type: Some("tcp".to_owned()),
+            ..Default::default()
+        };
+        assert!(tcp("mail.example.gov").validate().is_err());
+        assert!(tcp("2001:db8::1").validate().is_err());
+        assert!(tcp("[2001:db8::1]").validate().is_err());
+        assert!(tcp("mail.example.gov:25").validate().is_ok());
+        assert!(tcp("[2001:db8::1]:25").validate().is_ok());
+
+        // Other probe types have a default port
+        let tls = UpTarget {
+            url: "example.gov".to_owned(),
+            probe_type: Some("tls".to_owned()),
+            ..Default::default()
+        };
+        assert!(tls.validate().is_ok());
     }
 }
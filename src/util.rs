@@ -42,7 +42,7 @@ pub fn check_for_error(
         // If the JSON value is an array, check the "success" key for each item
         JsonValue::Array(v) => {
             for item in v.iter() {
-                if item.as_object().map(|obj| obj.get("success")).flatten()
+                if item.as_object().and_then(|obj| obj.get("success"))
                     != Some(&JsonValue::Bool(true))
                 {
                     // If any item does not have "success" set to true, return an error